@@ -0,0 +1,96 @@
+//! Measure UDP ASSOCIATE throughput of a running sock5s instance.
+//!
+//! Usage: cargo run --release --example udp_pps -- <PROXY HOST:PORT> [SECONDS] [PAYLOAD]
+//!
+//! A local echo server is used as the target, so the reported rate is the
+//! number of datagrams that made the full round trip through the proxy.
+
+use std::env;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let proxy: SocketAddr = args.next().ok_or("Missing proxy address!")?.parse()?;
+    let seconds: u64 = args.next().map_or(Ok(5), |x| x.parse())?;
+    let payload: usize = args.next().map_or(Ok(64), |x| x.parse())?;
+
+    let echo = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let echo_addr = echo.local_addr()?;
+    tokio::spawn(async move {
+        let mut buf = vec![0; 1500];
+        while let Ok((len, from)) = echo.recv_from(&mut buf).await {
+            let _ = echo.send_to(&buf[..len], from).await;
+        }
+    });
+
+    let mut control = TcpStream::connect(proxy).await?;
+    control.write_all(b"\x05\x01\x00").await?;
+    let mut reply = [0; 2];
+    control.read_exact(&mut reply).await?;
+    if reply != *b"\x05\x00" {
+        return Err("Authentication rejected!".into());
+    }
+
+    let udp_socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let mut request = b"\x05\x03\x00\x01".to_vec();
+    request.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+    request.extend_from_slice(&udp_socket.local_addr()?.port().to_be_bytes());
+    control.write_all(&request).await?;
+
+    let mut reply = [0; 10];
+    control.read_exact(&mut reply).await?;
+    if reply[1] != 0 || reply[3] != 1 {
+        return Err(format!("UDP ASSOCIATE failed: {:#04x}!", reply[1]).into());
+    }
+    let relay = SocketAddr::from((
+        Ipv4Addr::new(reply[4], reply[5], reply[6], reply[7]),
+        u16::from_be_bytes([reply[8], reply[9]]),
+    ));
+    udp_socket.connect(relay).await?;
+    let udp_socket = Arc::new(udp_socket);
+
+    let mut packet = b"\x00\x00\x00\x01".to_vec();
+    packet.extend_from_slice(&Ipv4Addr::LOCALHOST.octets());
+    packet.extend_from_slice(&echo_addr.port().to_be_bytes());
+    packet.resize(packet.len() + payload, 0xaa);
+
+    let running = Arc::new(AtomicBool::new(true));
+    let sent = Arc::new(AtomicU64::new(0));
+    let sender = {
+        let (udp_socket, running, sent) = (udp_socket.clone(), running.clone(), sent.clone());
+        tokio::spawn(async move {
+            while running.load(Ordering::Relaxed) {
+                if udp_socket.send(&packet).await.is_ok() {
+                    sent.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        })
+    };
+
+    let mut received = 0u64;
+    let mut buf = vec![0; 1500];
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(seconds);
+    while let Ok(Ok(_)) = tokio::time::timeout_at(deadline.into(), udp_socket.recv(&mut buf)).await
+    {
+        received += 1;
+    }
+    running.store(false, Ordering::Relaxed);
+    let _ = sender.await;
+
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("sent:     {} packets", sent.load(Ordering::Relaxed));
+    println!("received: {received} packets");
+    println!("rate:     {:.0} packets/s", received as f64 / elapsed);
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sock5s::codec::{Decode, Encode};
+use sock5s::{Socks5Host, Socks5Target};
+
+// Addresses of the domain type, with the length and name from the input.
+fuzz_target!(|data: &[u8]| {
+    let address = [&[3], data].concat();
+    if let Ok((target, len)) = Socks5Target::decode(&address) {
+        assert!(matches!(target.0, Socks5Host::Domain(_)));
+        let mut buf = Vec::new();
+        target.encode(&mut buf).unwrap();
+        assert_eq!(buf, address[..len]);
+
+        // Normalized names are ASCII and stay as they are when normalized
+        // again.
+        if let Ok(Socks5Host::Domain(x)) = target.0.normalize() {
+            assert!(x.is_ascii());
+            let again = Socks5Host::Domain(x.clone()).normalize();
+            assert!(matches!(again, Ok(Socks5Host::Domain(y)) if y == x));
+        }
+    }
+});
@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use sock5s::codec::{Decode, Encode, UdpHeader};
+
+// Decoding must not panic, and what decodes must encode to the same bytes.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((message, len)) = UdpHeader::decode(data) {
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        assert_eq!(buf, data[..len]);
+    }
+});
@@ -1,105 +1,236 @@
+use std::future::Future;
+
+use tokio::time::Instant;
+
 use super::*;
+use crate::auth::read_password;
 
 pub struct Socks5Acceptor {
     pub buf: Vec<u8>,
-    pub stream: TcpStream,
+    pub stream: Socks5Stream,
+    pub config: Arc<Config>,
+    pub session: Arc<Session>,
+    /// The bandwidth limit of the listener that accepted the client.
+    pub buckets: Option<Buckets>,
+    /// Counts the connection against the limit on connections.
+    pub connection: Option<ConnectionPermit>,
 }
 
 impl Socks5Acceptor {
     pub async fn authenticate(&mut self) -> Result<()> {
-        self.buf.resize(2, 0);
-        self.stream.read_exact(&mut self.buf).await?;
-
-        if self.buf[0] != 5 {
-            return Err("Not socks5 request!".into());
+        self.buf.clear();
+        let greeting: Greeting = match read_message(&mut self.stream, &mut self.buf).await {
+            Ok(x) => x,
+            Err(e) => {
+                if let Error::Codec(_) = e {
+                    self.session.metrics.reject(Reject::Protocol);
+                }
+                return Err(e);
+            }
+        };
+        let methods = greeting.methods;
+
+        if self.session.user.get().is_some() {
+            // A verified client certificate already identifies the user, so
+            // the username/password sub-negotiation is a formality.
+            if methods.contains(&0) {
+                self.select_method(0).await?;
+                return Ok(());
+            } else if methods.contains(&2) {
+                self.select_method(2).await?;
+                if let Err(e) = read_password(&mut self.stream).await {
+                    if let Error::Codec(_) = e {
+                        self.session.metrics.reject(Reject::Protocol);
+                    }
+                    return Err(e);
+                }
+                self.stream.write_all(b"\x01\x00").await?;
+                return Ok(());
+            }
         }
 
-        self.buf.resize(2 + self.buf[1] as usize, 0);
-        self.stream.read_exact(&mut self.buf[2..]).await?;
+        let config = self.config.clone();
+        let authenticators = match config.authenticators.as_slice() {
+            [] => &[Arc::new(NoAuth) as Arc<dyn Authenticator>][..],
+            x => x,
+        };
+        let selected = authenticators.iter().find_map(|auth| {
+            let method = auth.methods().iter().find(|x| methods.contains(x))?;
+            Some((auth, *method))
+        });
+        let Some((auth, method)) = selected else {
+            self.session.metrics.reject(Reject::AuthMethod);
+            self.select_method(NO_ACCEPTABLE_METHODS).await?;
+            return Err(Error::Denied("No supported authentication method!".into()));
+        };
 
-        if !self.buf[2..].contains(&0) {
-            self.stream.write_all(b"\x05\xff").await?;
-            return Err("No supported authentication method!".into());
+        self.select_method(method).await?;
+        match auth
+            .authenticate(method, &mut self.stream, &self.session.peer)
+            .await
+        {
+            Ok(Some(x)) => {
+                let _ = self.session.user.set(x);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.session.metrics.reject(match e {
+                    Error::Codec(_) => Reject::Protocol,
+                    _ => Reject::Auth,
+                });
+                return Err(e);
+            }
         }
 
-        self.stream.write_all(b"\x05\x00").await?;
         Ok(())
     }
 
-    pub async fn accept(mut self) -> Result<()> {
-        self.authenticate().await?;
-        let (command, target) = self.accept_command().await?;
-        let target = Socks5Target::try_from(target)?;
+    pub async fn accept(mut self) -> Result<&'static str> {
+        let deadline = self.config.handshake_timeout.map(|x| Instant::now() + x);
+        let metrics = self.session.metrics.clone();
+        handshake(deadline, &metrics, self.accept_proxy_header()).await?;
+        if let Some(target) = self.config.forward.clone() {
+            return self.forward(target, "FORWARD").await;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(mode) = self.config.transparent {
+            return self.accept_transparent(mode).await;
+        }
+
+        #[cfg(feature = "tls")]
+        if let Some(tls) = self.config.tls.clone() {
+            let identity = self.config.tls_identity;
+            self = handshake(deadline, &metrics, self.accept_tls(&tls, identity)).await?;
+        }
+
+        handshake(deadline, &metrics, self.authenticate()).await?;
+        let Request { command, target } =
+            handshake(deadline, &metrics, self.accept_request()).await?;
+        let udp = command == Command::UdpAssociate;
+        let _ = self.session.target.set(target.clone());
+        let _ = self
+            .session
+            .command
+            .set(if udp { "UDP" } else { "CONNECT" });
+        let _permit = match self.admit(udp) {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = self.closed(&e).await;
+                return Err(e);
+            }
+        };
+        Metrics::inc(&metrics.accepted);
 
-        if command == 3 {
+        if udp {
             self.associate_udp(target).await
         } else {
             self.connect(target).await
         }
     }
 
-    pub async fn accept_command(&mut self) -> Result<(u8, &[u8])> {
-        self.buf.resize(5, 0);
-        self.stream.read_exact(&mut self.buf).await?;
-
-        if self.buf[0] != 5 || self.buf[2] != 0 {
-            return Err("Invalid request!".into());
+    /// Sets up the bandwidth limits of the session, once the client is
+    /// known.
+    pub fn shape(&self) {
+        if let Some(shaper) = &self.config.shaper {
+            let limiters = shaper.session(self.buckets.as_ref(), &self.session);
+            let _ = self.session.limiters.set(limiters);
         }
+    }
 
-        let len = match Socks5Target::target_len(&self.buf[3..]) {
-            Ok(x) => x + 3,
-            Err(e) => {
-                self.stream.write_all(b"\x05\x08").await?;
-                return Err(e);
-            }
+    /// Counts the session against the session limits, once the client and
+    /// the command are known.
+    pub fn admit(&self, udp: bool) -> Result<Option<Permit>> {
+        let Some(admission) = &self.config.admission else {
+            return Ok(None);
         };
+        let permit = admission.admit(&self.session, udp).inspect_err(|_| {
+            self.session.metrics.reject(Reject::Limit);
+        })?;
+        Ok(Some(permit))
+    }
+
+    async fn select_method(&mut self, method: u8) -> Result<()> {
+        write_message(&mut self.stream, &MethodSelection { method }).await
+    }
 
-        self.buf.resize(len, 0);
-        self.stream.read_exact(&mut self.buf[5..]).await?;
+    pub async fn accept_request(&mut self) -> Result<Request> {
+        self.buf.clear();
+        let mut request: Request = match read_message(&mut self.stream, &mut self.buf).await {
+            Ok(x) => x,
+            Err(e @ Error::Codec(_)) => return Err(self.reject_request(e).await),
+            Err(e) => return Err(e),
+        };
 
-        if self.buf[1] != 1 && self.buf[1] != 3 {
-            self.stream.write_all(b"\x05\x07").await?;
-            return Err("Unsupported request command!".into());
+        // UDP relaying needs the client's IP address.
+        let udp = request.command == Command::UdpAssociate && self.stream.tcp().is_some();
+        if request.command != Command::Connect && !udp {
+            let e = CodecError::Command(request.command as u8).into();
+            return Err(self.reject_request(e).await);
         }
 
-        Ok((self.buf[1], &self.buf[3..]))
+        request.target.0 = match request.target.0.normalize() {
+            Ok(x) => x,
+            Err(e) => return Err(self.reject_request(e).await),
+        };
+        Ok(request)
+    }
+
+    /// Counts and answers a request that cannot be served, returning the
+    /// error.
+    async fn reject_request(&mut self, e: Error) -> Error {
+        self.session.metrics.reject(match e {
+            Error::Codec(CodecError::Command(_)) => Reject::Command,
+            Error::Codec(CodecError::AddressType(_) | CodecError::Domain)
+            | Error::InvalidDomain(_) => Reject::AddressType,
+            _ => Reject::Protocol,
+        });
+        let _ = self.closed(&e).await;
+        e
     }
 
     pub async fn connected(&mut self, local_addr: SocketAddr) -> Result<()> {
-        let mut reply = b"\x05\x00\x00".to_vec();
-        reply.put_socks5_addr(local_addr);
-        self.stream.write_all(&reply).await?;
-        Ok(())
+        write_message(&mut self.stream, &Reply::success(local_addr.into())).await
     }
 
-    pub async fn closed(mut self, resp: u8) -> Result<()> {
-        // resp:
-        //   0x00 succeeded
-        //   0x01 general SOCKS server failure
-        //   0x02 connection not allowed by ruleset
-        //   0x03 Network unreachable
-        //   0x04 Host unreachable
-        //   0x05 Connection refused
-        //   0x06 TTL expired
-        //   0x07 Command not supported
-        //   0x08 Address type not supported
-        //   0x09 to 0xff unassigned
-        self.stream
-            .write_all(&[&[0x05, 0x01, resp], &self.buf[3..]].concat())
-            .await?;
-        Ok(())
+    /// Tells the client why its request failed.
+    pub async fn closed(&mut self, e: &Error) -> Result<()> {
+        write_message(&mut self.stream, &Reply::failure(e.reply_code())).await
     }
+}
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.stream.peer_addr().unwrap()
+/// Runs a step of the handshake, failing once `deadline` has passed and
+/// counting that in `metrics`.
+async fn handshake<T>(
+    deadline: Option<Instant>,
+    metrics: &Metrics,
+    step: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    let Some(deadline) = deadline else {
+        return step.await;
+    };
+    match tokio::time::timeout_at(deadline, step).await {
+        Ok(x) => x,
+        Err(_) => {
+            metrics.reject(Reject::Protocol);
+            Err(Error::Protocol("Handshake timed out!".into()))
+        }
     }
 }
 
-impl From<TcpStream> for Socks5Acceptor {
-    fn from(stream: TcpStream) -> Self {
+impl Socks5Acceptor {
+    pub fn new(
+        stream: Socks5Stream,
+        client: Socks5Peer,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
         Self {
             stream,
+            config,
+            session: Arc::new(Session::new(client, metrics)),
             buf: Vec::with_capacity(64),
+            buckets: None,
+            connection: None,
         }
     }
 }
@@ -0,0 +1,115 @@
+use std::env;
+use std::fs::File;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixDatagram;
+
+use socket2::SockAddr;
+
+use super::*;
+
+/// The first file descriptor passed by systemd socket activation.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Returns the sockets passed by systemd socket activation (`LISTEN_FDS`).
+pub fn listen_fds() -> Result<Vec<RawFd>> {
+    let var = |name| env::var(name).ok().and_then(|x| x.parse::<u32>().ok());
+    if var("LISTEN_PID") != Some(std::process::id()) {
+        return Err("No sockets passed by systemd!".into());
+    }
+
+    match var("LISTEN_FDS") {
+        Some(n) if n > 0 => Ok((SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + n as RawFd).collect()),
+        _ => Err("No sockets passed by systemd!".into()),
+    }
+}
+
+/// Takes ownership of an inherited socket, checking that it is a stream
+/// socket and switching it to non-blocking mode.
+pub fn inherited_socket(fd: RawFd) -> Result<(Socket, SockAddr)> {
+    let socket = unsafe { Socket::from_raw_fd(fd) };
+    match socket.r#type() {
+        Ok(Type::STREAM) => {}
+        _ => return Err(format!("File descriptor {fd} is not a stream socket!").into()),
+    }
+    socket.set_nonblocking(true)?;
+    let local_addr = socket.local_addr()?;
+    Ok((socket, local_addr))
+}
+
+/// The client connection an inetd style supervisor passes as `fd`.
+pub fn inherited_stream(fd: RawFd) -> Result<(Socks5Stream, Socks5Peer)> {
+    let (socket, local_addr) = inherited_socket(fd)?;
+    if local_addr.is_unix() {
+        let stream = std::os::unix::net::UnixStream::from(OwnedFd::from(socket));
+        let stream = tokio::net::UnixStream::from_std(stream)?;
+        let peer = Socks5Peer::of_unix(&stream)?;
+        Ok((stream.into(), peer))
+    } else {
+        let stream = TcpStream::from_std(socket.into())?;
+        let peer = Socks5Peer::Inet(stream.peer_addr()?);
+        Ok((stream.into(), peer))
+    }
+}
+
+/// Points stdout and stderr at /dev/null and logs to syslog instead, for
+/// when they are the client connection as in inetd mode. This changes the
+/// whole process, so it is up to the program rather than the server.
+pub fn detach_stdio() -> io::Result<()> {
+    log::use_syslog();
+    let null = File::options().write(true).open("/dev/null")?;
+    for fd in [1, 2] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Sends a state change such as `READY=1` to the service manager, if any
+/// (`NOTIFY_SOCKET`).
+pub fn notify(state: &str) {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = send_notify(&path, state) {
+        debug!("Failed to notify the service manager: {e}");
+    }
+}
+
+fn send_notify(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::linux::net::SocketAddrExt;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::net::SocketAddr;
+
+        if let Some(name) = path.as_bytes().strip_prefix(b"@") {
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+            return Ok(());
+        }
+    }
+
+    socket.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// Sends `WATCHDOG=1` at half the interval the service manager asked for
+/// (`WATCHDOG_USEC`), until the runtime stops.
+pub async fn watchdog() {
+    let var = |name| env::var(name).ok().and_then(|x| x.parse::<u64>().ok());
+    if var("WATCHDOG_PID").is_some_and(|x| x != std::process::id() as u64) {
+        return;
+    }
+    let Some(usec) = var("WATCHDOG_USEC").filter(|x| *x > 0) else {
+        return;
+    };
+
+    let mut interval = tokio::time::interval(Duration::from_micros(usec / 2));
+    loop {
+        interval.tick().await;
+        notify("WATCHDOG=1");
+    }
+}
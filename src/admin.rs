@@ -0,0 +1,118 @@
+use std::fmt::Write;
+use std::sync::atomic::Ordering;
+use std::time::SystemTime;
+
+use super::*;
+use crate::http::{Request, Response};
+use crate::log::{Timestamp, write_json_string};
+
+const JSON: &str = "application/json";
+
+/// Serves the admin API:
+///
+/// - `GET /sessions` lists live sessions
+/// - `DELETE /sessions/<id>` kills one session
+/// - `DELETE /sessions?user=<user>` kills all sessions of a user
+/// - `DELETE /sessions?target=<host[:port]>` kills all sessions to a target
+///
+/// Requests must name the listen address as their `Host`, so that a web
+/// page cannot reach the API through a domain rebound to loopback.
+pub async fn serve(listener: TcpListener, sessions: Arc<Registry>) -> Result<()> {
+    let addr = listener.local_addr()?;
+    http::serve(listener, move |request: Request| {
+        let response = match request.host.as_deref() {
+            Some(host) if is_addr(host, addr) => handle(request, &sessions),
+            _ => Response::text(403, "Unexpected Host header\n"),
+        };
+        async move { response }
+    })
+    .await;
+    Ok(())
+}
+
+/// Whether `host`, from a `Host` header, is `addr`, the port being 80 if
+/// left out.
+fn is_addr(host: &str, addr: SocketAddr) -> bool {
+    match host.parse::<SocketAddr>() {
+        Ok(x) => x == addr,
+        Err(_) => {
+            let ip = host.trim_start_matches('[').trim_end_matches(']');
+            addr.port() == 80 && ip.parse::<IpAddr>() == Ok(addr.ip())
+        }
+    }
+}
+
+fn handle(request: Request, sessions: &Registry) -> Response {
+    let id = request.path.strip_prefix("/sessions/");
+    match (request.method.as_str(), request.path.as_str(), id) {
+        ("GET", "/sessions", _) => Response::new(200, JSON, list_sessions(sessions)),
+        ("DELETE", _, Some(id)) => match id.parse::<u64>() {
+            Ok(id) => killed(sessions.kill(|x| x.id == id)),
+            Err(_) => Response::text(400, "Invalid session id\n"),
+        },
+        ("DELETE", "/sessions", _) => {
+            if let Some(user) = request.query("user") {
+                killed(sessions.kill(|x| x.user.get().is_some_and(|x| x.user == user)))
+            } else if let Some(target) = request.query("target") {
+                killed(sessions.kill(|x| match x.target.get() {
+                    Some(t) => t.to_string() == target || t.0.to_string() == target,
+                    None => false,
+                }))
+            } else {
+                Response::text(400, "Expected a user or target filter\n")
+            }
+        }
+        (_, "/sessions", _) | (_, _, Some(_)) => Response::text(405, "Method not allowed\n"),
+        _ => Response::text(404, "Not found\n"),
+    }
+}
+
+fn killed(count: usize) -> Response {
+    Response::new(200, JSON, format!("{{\"killed\":{count}}}\n"))
+}
+
+fn list_sessions(sessions: &Registry) -> String {
+    let mut out = String::from("[");
+    let now = SystemTime::now();
+
+    for (i, session) in sessions.list().iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write_session(&mut out, session, now);
+    }
+
+    out.push_str("]\n");
+    out
+}
+
+fn write_session(out: &mut String, session: &Session, now: SystemTime) -> fmt::Result {
+    let elapsed = session.started.elapsed();
+    let traffic = &session.traffic;
+    let string_or_null = |out: &mut String, x: Option<String>| match x {
+        Some(x) => write_json_string(out, &x),
+        None => out.write_str("null"),
+    };
+
+    write!(
+        out,
+        "{{\"id\":{},\"client\":\"{}\",\"user\":",
+        session.id,
+        session.client()
+    )?;
+    string_or_null(out, session.user.get().map(|x| x.user.clone()))?;
+    out.push_str(",\"command\":");
+    string_or_null(out, session.command.get().map(|x| x.to_string()))?;
+    out.push_str(",\"target\":");
+    string_or_null(out, session.target.get().map(|x| x.to_string()))?;
+    write!(
+        out,
+        ",\"started\":\"{}\",\"duration_ms\":{},\"bytes_up\":{},\"bytes_down\":{},\"packets_up\":{},\"packets_down\":{}}}",
+        Timestamp(now - elapsed),
+        elapsed.as_millis(),
+        traffic.bytes_up.load(Ordering::Relaxed),
+        traffic.bytes_down.load(Ordering::Relaxed),
+        traffic.packets_up.load(Ordering::Relaxed),
+        traffic.packets_down.load(Ordering::Relaxed),
+    )
+}
@@ -0,0 +1,318 @@
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use super::*;
+
+/// The future returned by [`Authenticator::authenticate`].
+pub type AuthFuture<'a> = Pin<Box<dyn Future<Output = Result<Option<Identity>>> + Send + 'a>>;
+
+/// The client connection as seen by an authenticator.
+pub trait AuthStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AuthStream for T {}
+
+/// Who a client is, as established by authentication. It is logged, shown
+/// by the admin API and may be passed on in PROXY protocol headers.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub user: String,
+}
+
+/// Authenticates clients with one or more SOCKS5 methods.
+pub trait Authenticator: Send + Sync {
+    /// The methods handled, e.g. `[0x02]` for username/password. The server
+    /// selects the first one the client offers, in the order authenticators
+    /// were added.
+    fn methods(&self) -> &[u8];
+
+    /// Runs the sub-negotiation of `method` once it has been selected.
+    /// Returns the identity of the client, or `None` for an anonymous one.
+    /// An error closes the connection and counts as a failed attempt.
+    fn authenticate<'a>(
+        &'a self,
+        method: u8,
+        stream: &'a mut dyn AuthStream,
+        peer: &'a Socks5Peer,
+    ) -> AuthFuture<'a>;
+}
+
+/// Accepts every client without authentication (method 0x00).
+pub struct NoAuth;
+
+/// Checks usernames and passwords (RFC 1929) against a fixed table.
+#[derive(Clone, Debug, Default)]
+pub struct StaticUsers(HashMap<String, String>);
+
+/// Checks usernames and passwords against a file of `USER:PASSWORD` lines,
+/// which is read again whenever it changes.
+pub struct FileUsers {
+    path: PathBuf,
+    users: Mutex<(Option<SystemTime>, Arc<StaticUsers>)>,
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        self.user.fmt(f)
+    }
+}
+
+impl From<String> for Identity {
+    fn from(user: String) -> Self {
+        Self { user }
+    }
+}
+
+impl Authenticator for NoAuth {
+    fn methods(&self) -> &[u8] {
+        &[0x00]
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _method: u8,
+        _stream: &'a mut dyn AuthStream,
+        _peer: &'a Socks5Peer,
+    ) -> AuthFuture<'a> {
+        Box::pin(async { Ok(None) })
+    }
+}
+
+impl StaticUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, user: String, password: String) {
+        self.0.insert(user, password);
+    }
+
+    /// Parses `USER:PASSWORD`.
+    pub fn parse_user(s: &str) -> std::result::Result<(String, String), String> {
+        match s.split_once(':') {
+            Some((user, password)) if !user.is_empty() && user.len() <= 255 => {
+                Ok((user.to_owned(), password.to_owned()))
+            }
+            _ => Err(format!("Invalid user: {s}!")),
+        }
+    }
+
+    fn check(&self, user: &[u8], password: &[u8]) -> bool {
+        let expected = std::str::from_utf8(user).ok().and_then(|x| self.0.get(x));
+        expected.is_some_and(|x| constant_time_eq(x.as_bytes(), password))
+    }
+}
+
+/// Compares without returning early, so that the time taken does not tell
+/// how much of a password was right.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+impl FromIterator<(String, String)> for StaticUsers {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl Authenticator for StaticUsers {
+    fn methods(&self) -> &[u8] {
+        &[0x02]
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _method: u8,
+        stream: &'a mut dyn AuthStream,
+        _peer: &'a Socks5Peer,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move { check_password(stream, self).await })
+    }
+}
+
+impl FileUsers {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let modified = fs::metadata(&path)?.modified().ok();
+        let users = Arc::new(Self::read(&path)?);
+        Ok(Self {
+            path,
+            users: Mutex::new((modified, users)),
+        })
+    }
+
+    fn read(path: &Path) -> Result<StaticUsers> {
+        let mut users = StaticUsers::new();
+        for (i, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, password) = StaticUsers::parse_user(line)
+                .map_err(|_| format!("Invalid user in {}:{}!", path.display(), i + 1))?;
+            users.insert(user, password);
+        }
+        Ok(users)
+    }
+
+    /// The current users, read again if the file has changed. The file is
+    /// checked on the blocking pool, without holding the lock.
+    async fn users(&self) -> Arc<StaticUsers> {
+        let (known, users) = self.users.lock().unwrap().clone();
+        let path = self.path.clone();
+        let changed = tokio::task::spawn_blocking(move || {
+            let modified = fs::metadata(&path).and_then(|x| x.modified()).ok();
+            (modified != known).then(|| (modified, Self::read(&path)))
+        })
+        .await;
+        match changed {
+            Ok(Some((modified, Ok(x)))) => {
+                let x = Arc::new(x);
+                *self.users.lock().unwrap() = (modified, x.clone());
+                x
+            }
+            Ok(Some((_, Err(e)))) => {
+                log!(Level::Warn, "Failed to read {}: {e}", self.path.display());
+                users
+            }
+            _ => users,
+        }
+    }
+}
+
+impl Authenticator for FileUsers {
+    fn methods(&self) -> &[u8] {
+        &[0x02]
+    }
+
+    fn authenticate<'a>(
+        &'a self,
+        _method: u8,
+        stream: &'a mut dyn AuthStream,
+        _peer: &'a Socks5Peer,
+    ) -> AuthFuture<'a> {
+        Box::pin(async move {
+            let users = self.users().await;
+            check_password(stream, &users).await
+        })
+    }
+}
+
+/// Reads an RFC 1929 username/password request.
+pub async fn read_password(stream: &mut dyn AuthStream) -> Result<UserPassAuth> {
+    read_message(stream, &mut Vec::new()).await
+}
+
+async fn check_password(
+    stream: &mut dyn AuthStream,
+    users: &StaticUsers,
+) -> Result<Option<Identity>> {
+    let UserPassAuth { user, password } = read_password(stream).await?;
+    if !users.check(&user, &password) {
+        stream.write_all(b"\x01\x01").await?;
+        return Err(Error::Denied("Invalid username or password!".into()));
+    }
+
+    stream.write_all(b"\x01\x00").await?;
+    Ok(Some(Identity::from(
+        String::from_utf8_lossy(&user).into_owned(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    /// A users file of this test, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("sock5s-{}-{name}", std::process::id()));
+            fs::write(&path, contents).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    /// Runs `auth` against a client sending `user` and `password`, and
+    /// returns the result and the reply.
+    async fn authenticate(
+        auth: &dyn Authenticator,
+        user: &str,
+        password: &str,
+    ) -> (Result<Option<Identity>>, Vec<u8>) {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let mut request = vec![0x01, user.len() as u8];
+        request.extend_from_slice(user.as_bytes());
+        request.push(password.len() as u8);
+        request.extend_from_slice(password.as_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let peer = Socks5Peer::Inet(([192, 0, 2, 1], 40000).into());
+        let result = auth.authenticate(0x02, &mut server, &peer).await;
+        drop(server);
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        (result, reply)
+    }
+
+    #[test]
+    fn parse_users_file() {
+        let file = TempFile::new(
+            "parse",
+            "# Comment\nalice:secret\r\n\n  \nbob:pass:word\ncarol:\n",
+        );
+        let users = FileUsers::read(&file.0).unwrap();
+        assert!(users.check(b"alice", b"secret"));
+        assert!(users.check(b"bob", b"pass:word"));
+        assert!(users.check(b"carol", b""));
+        assert!(!users.check(b"alice", b"secret\r"));
+        assert!(!users.check(b"# Comment", b""));
+
+        let file = TempFile::new("invalid", "alice:secret\nbob\n");
+        let e = FileUsers::read(&file.0).err().unwrap();
+        assert!(e.to_string().ends_with(":2!"), "{e}");
+    }
+
+    #[tokio::test]
+    async fn check_passwords() {
+        let users: StaticUsers = [("alice".into(), "secret".into())].into_iter().collect();
+        let (result, reply) = authenticate(&users, "alice", "secret").await;
+        assert_eq!(result.unwrap(), Some(Identity::from("alice".to_owned())));
+        assert_eq!(reply, b"\x01\x00");
+
+        let (result, reply) = authenticate(&users, "alice", "secreT").await;
+        assert!(matches!(result, Err(Error::Denied(_))));
+        assert_eq!(reply, b"\x01\x01");
+        let (result, _) = authenticate(&users, "mallory", "secret").await;
+        assert!(matches!(result, Err(Error::Denied(_))));
+    }
+
+    #[tokio::test]
+    async fn reread_users_file() {
+        let file = TempFile::new("reread", "alice:secret\n");
+        let users = FileUsers::open(&file.0).unwrap();
+        assert!(authenticate(&users, "alice", "secret").await.0.is_ok());
+
+        fs::write(&file.0, "alice:changed\n").unwrap();
+        let later = SystemTime::now() + Duration::from_secs(10);
+        File::options()
+            .write(true)
+            .open(&file.0)
+            .unwrap()
+            .set_modified(later)
+            .unwrap();
+        assert!(authenticate(&users, "alice", "secret").await.0.is_err());
+        assert!(authenticate(&users, "alice", "changed").await.0.is_ok());
+    }
+}
@@ -0,0 +1,282 @@
+use std::ops::{Deref, DerefMut};
+
+use super::*;
+
+/// A client for SOCKS5 servers, supporting no authentication and
+/// username/password (RFC 1929).
+#[derive(Clone, Debug)]
+pub struct Socks5Client {
+    proxy: String,
+    auth: Option<(String, String)>,
+}
+
+/// A connection relayed by a SOCKS5 server. It dereferences to the
+/// underlying `TcpStream` and can be read and written like it.
+#[derive(Debug)]
+pub struct Socks5ClientStream {
+    stream: TcpStream,
+    /// The address the server reported for the relay.
+    pub bound: Socks5Target,
+}
+
+/// A BIND request waiting for the incoming connection.
+#[derive(Debug)]
+pub struct Socks5Bind {
+    stream: TcpStream,
+    /// The address the server listens on for the incoming connection.
+    pub bound: Socks5Target,
+}
+
+/// A UDP association with a SOCKS5 server. Datagrams are sent to and
+/// received from targets through the relay, with the SOCKS5 UDP header
+/// added and removed.
+#[derive(Debug)]
+pub struct Socks5UdpSocket {
+    /// The association lasts as long as this connection.
+    _control: TcpStream,
+    udp_socket: UdpSocket,
+    /// The address of the relay.
+    pub relay: SocketAddr,
+}
+
+impl Socks5Client {
+    /// A client for the SOCKS5 server at `HOST:PORT`.
+    pub fn new(proxy: impl Into<String>) -> Self {
+        Self {
+            proxy: proxy.into(),
+            auth: None,
+        }
+    }
+
+    /// Offers username/password authentication.
+    pub fn with_auth(mut self, user: impl Into<String>, password: impl Into<String>) -> Self {
+        self.auth = Some((user.into(), password.into()));
+        self
+    }
+
+    /// Connects to the server and authenticates.
+    async fn handshake(&self) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect(self.proxy.as_str()).await?;
+        let methods = match &self.auth {
+            Some(_) => vec![0, 2],
+            None => vec![0],
+        };
+        write_message(&mut stream, &Greeting { methods }).await?;
+
+        let selection: MethodSelection = read_message(&mut stream, &mut Vec::new()).await?;
+        match (selection.method, &self.auth) {
+            (0, _) => {}
+            (2, Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    return Err("Username or password too long!".into());
+                }
+                let auth = UserPassAuth {
+                    user: user.as_bytes().to_vec(),
+                    password: password.as_bytes().to_vec(),
+                };
+                write_message(&mut stream, &auth).await?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status).await?;
+                if status[1] != 0 {
+                    return Err(Error::Upstream("SOCKS5 authentication failed!".into()));
+                }
+            }
+            _ => {
+                return Err(Error::Upstream(
+                    "No acceptable SOCKS5 authentication method!".into(),
+                ));
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Sends a request and reads the reply, returning the address in it.
+    async fn request(
+        stream: &mut TcpStream,
+        command: Command,
+        target: &Socks5Target,
+    ) -> Result<Socks5Target> {
+        write_message(
+            stream,
+            &Request {
+                command,
+                target: target.clone(),
+            },
+        )
+        .await?;
+        Self::reply(stream).await
+    }
+
+    async fn reply(stream: &mut TcpStream) -> Result<Socks5Target> {
+        // Check the reply code first, as some servers close the connection
+        // right after it on failure.
+        let mut buf = vec![0; 2];
+        stream.read_exact(&mut buf).await?;
+        if buf[0] == 5 && buf[1] != 0 {
+            return Err(Error::Reply(buf[1]));
+        }
+        let reply: Reply = read_message(stream, &mut buf).await?;
+        Ok(reply.bound)
+    }
+
+    /// Connects to `target` through the server.
+    pub async fn connect(&self, target: &Socks5Target) -> Result<Socks5ClientStream> {
+        let mut stream = self.handshake().await?;
+        let bound = Self::request(&mut stream, Command::Connect, target).await?;
+        Ok(Socks5ClientStream { stream, bound })
+    }
+
+    /// Asks the server to accept a connection from `target`, e.g. the data
+    /// connection of FTP.
+    pub async fn bind(&self, target: &Socks5Target) -> Result<Socks5Bind> {
+        let mut stream = self.handshake().await?;
+        let bound = Self::request(&mut stream, Command::Bind, target).await?;
+        Ok(Socks5Bind { stream, bound })
+    }
+
+    /// Sets up a UDP association.
+    pub async fn associate(&self) -> Result<Socks5UdpSocket> {
+        let mut control = self.handshake().await?;
+        let unspecified: IpAddr = match control.peer_addr()? {
+            SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+            SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+        };
+        let udp_socket = UdpSocket::bind((unspecified, 0)).await?;
+
+        // The server learns our address from the first datagram.
+        let local = Socks5Target(
+            Socks5Host::IpAddr(unspecified),
+            udp_socket.local_addr()?.port(),
+        );
+        let mut relay = match Self::request(&mut control, Command::UdpAssociate, &local).await? {
+            Socks5Target(Socks5Host::IpAddr(ip), port) => SocketAddr::new(ip, port),
+            Socks5Target(Socks5Host::Domain(_), _) => {
+                return Err(Error::Upstream("Unsupported UDP relay address!".into()));
+            }
+        };
+        if relay.ip().is_unspecified() {
+            relay.set_ip(control.peer_addr()?.ip());
+        }
+        udp_socket.connect(relay).await?;
+
+        Ok(Socks5UdpSocket {
+            _control: control,
+            udp_socket,
+            relay,
+        })
+    }
+}
+
+impl Socks5ClientStream {
+    pub fn into_inner(self) -> TcpStream {
+        self.stream
+    }
+}
+
+impl Deref for Socks5ClientStream {
+    type Target = TcpStream;
+
+    fn deref(&self) -> &TcpStream {
+        &self.stream
+    }
+}
+
+impl DerefMut for Socks5ClientStream {
+    fn deref_mut(&mut self) -> &mut TcpStream {
+        &mut self.stream
+    }
+}
+
+impl AsyncRead for Socks5ClientStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socks5ClientStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl Socks5Bind {
+    /// Waits for the incoming connection, returning it with the address it
+    /// came from.
+    pub async fn accept(mut self) -> Result<(Socks5ClientStream, Socks5Target)> {
+        let peer = Socks5Client::reply(&mut self.stream).await?;
+        let stream = Socks5ClientStream {
+            stream: self.stream,
+            bound: self.bound,
+        };
+        Ok((stream, peer))
+    }
+}
+
+impl Socks5UdpSocket {
+    /// Sends a datagram to `target` through the relay.
+    pub async fn send_to(&self, buf: &[u8], target: &Socks5Target) -> Result<usize> {
+        let mut packet = Vec::with_capacity(buf.len() + 22);
+        UdpHeader::new(target.clone()).encode(&mut packet)?;
+        packet.extend_from_slice(buf);
+        self.udp_socket.send(&packet).await?;
+        Ok(buf.len())
+    }
+
+    /// Receives a datagram and the target it came from. Fragments and
+    /// malformed datagrams are skipped.
+    pub async fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, Socks5Target)> {
+        let mut packet = vec![0; buf.len() + 262];
+        loop {
+            let len = self.udp_socket.recv(&mut packet).await?;
+            let Ok((header, offset)) = UdpHeader::decode(&packet[..len]) else {
+                continue;
+            };
+            if header.frag != 0 {
+                continue;
+            }
+
+            let data = &packet[offset..len];
+            let n = data.len().min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            return Ok((n, header.target));
+        }
+    }
+}
+
+impl UdpSession for Socks5UdpSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a Socks5Target) -> DialFuture<'a, ()> {
+        Box::pin(async move {
+            Socks5UdpSocket::send_to(self, buf, target).await?;
+            Ok(())
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> DialFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            loop {
+                // Relays answer with addresses; domains cannot be passed on.
+                if let (len, Socks5Target(Socks5Host::IpAddr(ip), port)) =
+                    Socks5UdpSocket::recv_from(self, buf).await?
+                {
+                    return Ok((len, SocketAddr::new(ip, port)));
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,558 @@
+//! The SOCKS5 wire format (RFC 1928, RFC 1929), without any I/O.
+//!
+//! Messages decode from the start of a byte buffer. When the buffer is too
+//! short, decoding fails with [`CodecError::Incomplete`], telling how many
+//! bytes are needed in total, so that callers can read exactly that much
+//! and try again without consuming anything that follows the message.
+
+use std::{error, result};
+
+use super::*;
+
+/// Decodes a message from the start of a buffer.
+pub trait Decode: Sized {
+    /// Returns the message and the number of bytes it took.
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError>;
+}
+
+/// Encodes a message at the end of a buffer.
+pub trait Encode {
+    /// Fails, leaving `buf` as it was, if a field does not fit.
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError>;
+}
+
+/// Why a message could not be decoded or encoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CodecError {
+    /// At least this many bytes are needed in total.
+    Incomplete(usize),
+    Version(u8),
+    /// A reserved field is not zero.
+    Reserved(u8),
+    Command(u8),
+    AddressType(u8),
+    /// A domain that is empty or not UTF-8.
+    Domain,
+    /// A length-prefixed field longer than 255 bytes.
+    TooLong,
+}
+
+/// The methods a client offers to authenticate with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Greeting {
+    pub methods: Vec<u8>,
+}
+
+/// The method the server selected, `0xff` if none.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MethodSelection {
+    pub method: u8,
+}
+
+/// An RFC 1929 username/password request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserPassAuth {
+    pub user: Vec<u8>,
+    pub password: Vec<u8>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Command {
+    Connect = 1,
+    Bind = 2,
+    UdpAssociate = 3,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub command: Command,
+    pub target: Socks5Target,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reply {
+    /// `0x00` on success, an RFC 1928 error code otherwise.
+    pub reply: u8,
+    pub bound: Socks5Target,
+}
+
+/// The header of datagrams relayed for UDP associations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UdpHeader {
+    /// Fragment number, `0` for a whole datagram.
+    pub frag: u8,
+    pub target: Socks5Target,
+}
+
+/// The method chosen when the server accepts none of the offered ones.
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+
+impl Display for CodecError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Incomplete(x) => write!(f, "Incomplete message, {x} bytes needed!"),
+            Self::Version(x) => write!(f, "Invalid version: {x}!"),
+            Self::Reserved(x) => write!(f, "Invalid reserved field: {x}!"),
+            Self::Command(x) => write!(f, "Unsupported request command: {x}!"),
+            Self::AddressType(x) => write!(f, "Invalid address type: {x}!"),
+            Self::Domain => "Invalid domain!".fmt(f),
+            Self::TooLong => "Field too long!".fmt(f),
+        }
+    }
+}
+
+impl error::Error for CodecError {}
+
+/// Fails with [`CodecError::Incomplete`] unless `buf` holds `len` bytes.
+fn need(buf: &[u8], len: usize) -> result::Result<(), CodecError> {
+    match buf.len() < len {
+        true => Err(CodecError::Incomplete(len)),
+        false => Ok(()),
+    }
+}
+
+fn version(buf: &[u8], expected: u8) -> result::Result<(), CodecError> {
+    need(buf, 1)?;
+    match buf[0] == expected {
+        true => Ok(()),
+        false => Err(CodecError::Version(buf[0])),
+    }
+}
+
+fn reserved(x: u8) -> result::Result<(), CodecError> {
+    match x {
+        0 => Ok(()),
+        x => Err(CodecError::Reserved(x)),
+    }
+}
+
+/// Decodes the address at `start`, returning it with the length of the
+/// message up to its end.
+fn decode_target(buf: &[u8], start: usize) -> result::Result<(Socks5Target, usize), CodecError> {
+    match Socks5Target::decode(&buf[start..]) {
+        Ok((target, len)) => Ok((target, start + len)),
+        Err(CodecError::Incomplete(x)) => Err(CodecError::Incomplete(start + x)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Appends a length-prefixed field.
+fn put_field(buf: &mut Vec<u8>, field: &[u8]) -> result::Result<(), CodecError> {
+    let len = u8::try_from(field.len()).map_err(|_| CodecError::TooLong)?;
+    buf.push(len);
+    buf.extend_from_slice(field);
+    Ok(())
+}
+
+impl Decode for Socks5Target {
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        need(buf, 2)?;
+        let len = match buf[0] {
+            1 => 7,
+            4 => 19,
+            3 => 4 + buf[1] as usize,
+            x => return Err(CodecError::AddressType(x)),
+        };
+        need(buf, len)?;
+
+        let port = u16::from_be_bytes([buf[len - 2], buf[len - 1]]);
+        let host = match buf[0] {
+            1 => Socks5Host::IpAddr(Ipv4Addr::from_octets(buf[1..5].try_into().unwrap()).into()),
+            4 => Socks5Host::IpAddr(Ipv6Addr::from_octets(buf[1..17].try_into().unwrap()).into()),
+            _ => match std::str::from_utf8(&buf[2..len - 2]) {
+                Ok(x) if !x.is_empty() => Socks5Host::Domain(x.to_owned()),
+                _ => return Err(CodecError::Domain),
+            },
+        };
+        Ok((Self(host, port), len))
+    }
+}
+
+impl Encode for Socks5Target {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        match &self.0 {
+            Socks5Host::IpAddr(IpAddr::V4(x)) => {
+                buf.push(1);
+                buf.extend_from_slice(&x.octets());
+            }
+            Socks5Host::IpAddr(IpAddr::V6(x)) => {
+                buf.push(4);
+                buf.extend_from_slice(&x.octets());
+            }
+            Socks5Host::Domain(x) => {
+                if x.len() > 255 {
+                    return Err(CodecError::TooLong);
+                }
+                buf.push(3);
+                put_field(buf, x.as_bytes())?;
+            }
+        }
+        buf.extend_from_slice(&self.1.to_be_bytes());
+        Ok(())
+    }
+}
+
+impl Decode for Greeting {
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        version(buf, 5)?;
+        need(buf, 2)?;
+        let len = 2 + buf[1] as usize;
+        need(buf, len)?;
+        let methods = buf[2..len].to_vec();
+        Ok((Self { methods }, len))
+    }
+}
+
+impl Encode for Greeting {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        if self.methods.len() > 255 {
+            return Err(CodecError::TooLong);
+        }
+        buf.push(5);
+        put_field(buf, &self.methods)
+    }
+}
+
+impl Decode for MethodSelection {
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        version(buf, 5)?;
+        need(buf, 2)?;
+        Ok((Self { method: buf[1] }, 2))
+    }
+}
+
+impl Encode for MethodSelection {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        buf.extend_from_slice(&[5, self.method]);
+        Ok(())
+    }
+}
+
+impl Decode for UserPassAuth {
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        version(buf, 1)?;
+        need(buf, 2)?;
+        let user_end = 2 + buf[1] as usize;
+        need(buf, user_end + 1)?;
+        let len = user_end + 1 + buf[user_end] as usize;
+        need(buf, len)?;
+        let user = buf[2..user_end].to_vec();
+        let password = buf[user_end + 1..len].to_vec();
+        Ok((Self { user, password }, len))
+    }
+}
+
+impl Encode for UserPassAuth {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        if self.user.len() > 255 || self.password.len() > 255 {
+            return Err(CodecError::TooLong);
+        }
+        buf.push(1);
+        put_field(buf, &self.user)?;
+        put_field(buf, &self.password)
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = CodecError;
+
+    fn try_from(x: u8) -> result::Result<Self, CodecError> {
+        Ok(match x {
+            1 => Self::Connect,
+            2 => Self::Bind,
+            3 => Self::UdpAssociate,
+            x => return Err(CodecError::Command(x)),
+        })
+    }
+}
+
+impl Decode for Request {
+    /// The whole request is checked for length before the command, so that
+    /// a server can tell unsupported commands from truncated requests.
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        version(buf, 5)?;
+        need(buf, 3)?;
+        reserved(buf[2])?;
+        let (target, len) = decode_target(buf, 3)?;
+        let command = Command::try_from(buf[1])?;
+        Ok((Self { command, target }, len))
+    }
+}
+
+impl Encode for Request {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        let start = buf.len();
+        buf.extend_from_slice(&[5, self.command as u8, 0]);
+        self.target.encode(buf).inspect_err(|_| buf.truncate(start))
+    }
+}
+
+impl Reply {
+    /// A successful reply with the address bound for the client.
+    pub fn success(bound: Socks5Target) -> Self {
+        Self { reply: 0, bound }
+    }
+
+    /// A failure reply, which carries no meaningful address.
+    pub fn failure(reply: u8) -> Self {
+        let bound = Socks5Target(Socks5Host::IpAddr(Ipv4Addr::UNSPECIFIED.into()), 0);
+        Self { reply, bound }
+    }
+}
+
+impl Decode for Reply {
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        version(buf, 5)?;
+        need(buf, 3)?;
+        reserved(buf[2])?;
+        let (bound, len) = decode_target(buf, 3)?;
+        Ok((
+            Self {
+                reply: buf[1],
+                bound,
+            },
+            len,
+        ))
+    }
+}
+
+impl Encode for Reply {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        let start = buf.len();
+        buf.extend_from_slice(&[5, self.reply, 0]);
+        self.bound.encode(buf).inspect_err(|_| buf.truncate(start))
+    }
+}
+
+impl UdpHeader {
+    pub fn new(target: Socks5Target) -> Self {
+        Self { frag: 0, target }
+    }
+}
+
+impl Decode for UdpHeader {
+    /// Returns the header and the offset of the data that follows it.
+    fn decode(buf: &[u8]) -> result::Result<(Self, usize), CodecError> {
+        need(buf, 3)?;
+        reserved(buf[0] | buf[1])?;
+        let (target, len) = decode_target(buf, 3)?;
+        Ok((
+            Self {
+                frag: buf[2],
+                target,
+            },
+            len,
+        ))
+    }
+}
+
+impl Encode for UdpHeader {
+    fn encode(&self, buf: &mut Vec<u8>) -> result::Result<(), CodecError> {
+        let start = buf.len();
+        buf.extend_from_slice(&[0, 0, self.frag]);
+        self.target.encode(buf).inspect_err(|_| buf.truncate(start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v4(port: u16) -> Socks5Target {
+        Socks5Target(Socks5Host::IpAddr(Ipv4Addr::new(192, 0, 2, 1).into()), port)
+    }
+
+    fn v6(port: u16) -> Socks5Target {
+        Socks5Target(Socks5Host::IpAddr(Ipv6Addr::LOCALHOST.into()), port)
+    }
+
+    fn domain(port: u16) -> Socks5Target {
+        Socks5Target(Socks5Host::Domain("example.com".into()), port)
+    }
+
+    /// Encodes `message`, decodes it back, and checks that every shorter
+    /// prefix is incomplete, needing more than it has but no more than all.
+    fn round_trip<T: Decode + Encode + PartialEq + fmt::Debug>(message: T) -> Vec<u8> {
+        let mut buf = Vec::new();
+        message.encode(&mut buf).unwrap();
+        assert_eq!(T::decode(&buf), Ok((message, buf.len())));
+
+        let mut trailing = buf.clone();
+        trailing.extend_from_slice(b"next");
+        assert_eq!(T::decode(&trailing).unwrap().1, buf.len());
+
+        for i in 0..buf.len() {
+            match T::decode(&buf[..i]) {
+                Err(CodecError::Incomplete(x)) => assert!(i < x && x <= buf.len(), "{i}: {x}"),
+                x => panic!("{i}: {x:?}"),
+            }
+        }
+        buf
+    }
+
+    fn decode<T: Decode + fmt::Debug>(buf: &[u8]) -> CodecError {
+        T::decode(buf).unwrap_err()
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(round_trip(v4(80)), b"\x01\xc0\x00\x02\x01\x00\x50");
+        assert_eq!(round_trip(v6(443)).len(), 19);
+        assert_eq!(round_trip(domain(53)), b"\x03\x0bexample.com\x00\x35");
+
+        assert_eq!(
+            decode::<Socks5Target>(b"\x02\x00"),
+            CodecError::AddressType(2)
+        );
+        assert_eq!(
+            decode::<Socks5Target>(b"\x03\x00\x00\x50"),
+            CodecError::Domain
+        );
+        assert_eq!(
+            decode::<Socks5Target>(b"\x03\x01\xff\x00\x50"),
+            CodecError::Domain
+        );
+
+        let long = Socks5Target(Socks5Host::Domain("x".repeat(256)), 80);
+        let mut buf = b"keep".to_vec();
+        assert_eq!(long.encode(&mut buf), Err(CodecError::TooLong));
+        assert_eq!(buf, b"keep");
+    }
+
+    #[test]
+    fn greeting() {
+        assert_eq!(
+            round_trip(Greeting {
+                methods: vec![0, 2]
+            }),
+            b"\x05\x02\x00\x02"
+        );
+        round_trip(Greeting { methods: vec![] });
+
+        assert_eq!(decode::<Greeting>(b"\x04\x01\x00"), CodecError::Version(4));
+        let greeting = Greeting {
+            methods: vec![0; 256],
+        };
+        assert_eq!(greeting.encode(&mut Vec::new()), Err(CodecError::TooLong));
+    }
+
+    #[test]
+    fn method_selection() {
+        assert_eq!(round_trip(MethodSelection { method: 2 }), b"\x05\x02");
+        assert_eq!(
+            decode::<MethodSelection>(b"\x01\x02"),
+            CodecError::Version(1)
+        );
+    }
+
+    #[test]
+    fn user_pass_auth() {
+        let auth = UserPassAuth {
+            user: b"alice".to_vec(),
+            password: b"secret".to_vec(),
+        };
+        assert_eq!(round_trip(auth), b"\x01\x05alice\x06secret");
+        round_trip(UserPassAuth {
+            user: vec![],
+            password: vec![],
+        });
+
+        assert_eq!(
+            decode::<UserPassAuth>(b"\x05\x00\x00"),
+            CodecError::Version(5)
+        );
+        let auth = UserPassAuth {
+            user: b"alice".to_vec(),
+            password: vec![0; 256],
+        };
+        assert_eq!(auth.encode(&mut Vec::new()), Err(CodecError::TooLong));
+    }
+
+    #[test]
+    fn request() {
+        for (command, target) in [
+            (Command::Connect, v4(80)),
+            (Command::Bind, v6(21)),
+            (Command::UdpAssociate, domain(0)),
+        ] {
+            round_trip(Request { command, target });
+        }
+
+        assert_eq!(
+            decode::<Request>(b"\x04\x01\x00\x01\0\0\0\0\0\0"),
+            CodecError::Version(4)
+        );
+        assert_eq!(
+            decode::<Request>(b"\x05\x01\x01\x01\0\0\0\0\0\0"),
+            CodecError::Reserved(1)
+        );
+        assert_eq!(
+            decode::<Request>(b"\x05\x01\x00\x05\0\0\0\0\0\0"),
+            CodecError::AddressType(5)
+        );
+        // Unsupported commands are only reported for whole requests.
+        assert_eq!(
+            decode::<Request>(b"\x05\x09\x00\x01\0\0"),
+            CodecError::Incomplete(10)
+        );
+        assert_eq!(
+            decode::<Request>(b"\x05\x09\x00\x01\0\0\0\0\0\0"),
+            CodecError::Command(9)
+        );
+    }
+
+    #[test]
+    fn reply() {
+        assert_eq!(
+            round_trip(Reply::success(v4(1080))),
+            b"\x05\x00\x00\x01\xc0\x00\x02\x01\x04\x38"
+        );
+        round_trip(Reply::failure(0x05));
+        round_trip(Reply {
+            reply: 0,
+            bound: domain(1080),
+        });
+
+        assert_eq!(
+            decode::<Reply>(b"\x06\x00\x00\x01\0\0\0\0\0\0"),
+            CodecError::Version(6)
+        );
+        assert_eq!(
+            decode::<Reply>(b"\x05\x00\x02\x01\0\0\0\0\0\0"),
+            CodecError::Reserved(2)
+        );
+        assert_eq!(
+            decode::<Reply>(b"\x05\x00\x00\x00\0\0\0\0\0\0"),
+            CodecError::AddressType(0)
+        );
+    }
+
+    #[test]
+    fn udp_header() {
+        assert_eq!(
+            round_trip(UdpHeader::new(v4(53))),
+            b"\x00\x00\x00\x01\xc0\x00\x02\x01\x00\x35"
+        );
+        round_trip(UdpHeader {
+            frag: 1,
+            target: domain(53),
+        });
+
+        // The data following the header is not part of it.
+        let (header, offset) = UdpHeader::decode(b"\x00\x00\x00\x01\0\0\0\0\0\0data").unwrap();
+        assert_eq!((header.frag, offset), (0, 10));
+
+        assert_eq!(
+            decode::<UdpHeader>(b"\x00\x01\x00\x01\0\0\0\0\0\0"),
+            CodecError::Reserved(1)
+        );
+        assert_eq!(
+            decode::<UdpHeader>(b"\x00\x00\x00\x07\0\0\0\0\0\0"),
+            CodecError::AddressType(7)
+        );
+        let long = UdpHeader::new(Socks5Target(Socks5Host::Domain("x".repeat(256)), 53));
+        let mut buf = Vec::new();
+        assert_eq!(long.encode(&mut buf), Err(CodecError::TooLong));
+        assert!(buf.is_empty());
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "tls")]
+use tokio_rustls::TlsAcceptor;
+
+use crate::auth::Authenticator;
+use crate::dialer::Route;
+use crate::limits::Admission;
+use crate::proxy_protocol::{ProxyMode, ProxyRule};
+use crate::rules::RuleSet;
+use crate::shaper::Shaper;
+use crate::target::Socks5Target;
+#[cfg(feature = "tls")]
+use crate::tls::CertIdentity;
+#[cfg(target_os = "linux")]
+use crate::transparent::TransparentMode;
+use crate::util::Cidr;
+
+#[derive(Clone, Default)]
+pub struct Config {
+    /// How clients authenticate, in order of preference; empty accepts
+    /// anyone without authentication.
+    pub authenticators: Vec<Arc<dyn Authenticator>>,
+    /// How targets are dialed, checked in order; targets no route matches
+    /// are dialed directly.
+    pub routes: Vec<Route>,
+    /// How long a relay may stay half-closed before both sides are closed.
+    pub half_close_timeout: Option<Duration>,
+    /// How long a client may take from connecting until its request is
+    /// read, including any PROXY protocol header and TLS handshake.
+    pub handshake_timeout: Option<Duration>,
+    /// Which targets clients may reach.
+    pub rules: RuleSet,
+    /// Limits the bandwidth of relays when set.
+    pub shaper: Option<Arc<Shaper>>,
+    /// Limits the number of concurrent sessions when set.
+    pub admission: Option<Arc<Admission>>,
+    /// Whether clients send a PROXY protocol header first.
+    pub proxy_protocol: ProxyMode,
+    /// Sources trusted to send a PROXY protocol header; empty trusts none.
+    pub proxy_protocol_from: Vec<Cidr>,
+    /// Destinations that get a PROXY protocol header on connect.
+    pub send_proxy_protocol: Vec<ProxyRule>,
+    /// Whether outbound v2 headers carry the user of the session.
+    pub send_proxy_protocol_user: bool,
+    /// Relays every connection to this target instead of speaking SOCKS.
+    pub forward: Option<Socks5Target>,
+    /// Relays redirected connections to their original destination instead
+    /// of speaking SOCKS.
+    #[cfg(target_os = "linux")]
+    pub transparent: Option<TransparentMode>,
+    /// Wraps every accepted connection in TLS when set.
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsAcceptor>,
+    /// Which part of a client certificate names the user.
+    #[cfg(feature = "tls")]
+    pub tls_identity: CertIdentity,
+}
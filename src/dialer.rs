@@ -0,0 +1,324 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use super::*;
+
+/// The future returned by dialers.
+pub type DialFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Opens TCP connections to targets, directly or through another proxy.
+pub trait TcpDialer: Send + Sync {
+    fn dial<'a>(&'a self, target: &'a Socks5Target) -> DialFuture<'a, TcpStream>;
+
+    /// Whether domains are resolved here rather than by an upstream. They
+    /// are then resolved before dialing, so that the access rules see the
+    /// addresses, and only addresses are dialed.
+    fn resolves_locally(&self) -> bool {
+        false
+    }
+}
+
+/// Opens the outbound side of UDP associations.
+pub trait UdpDialer: Send + Sync {
+    fn bind(&self) -> DialFuture<'_, Box<dyn UdpSession>>;
+
+    /// Whether domains are resolved here, as for [`TcpDialer`].
+    fn resolves_locally(&self) -> bool {
+        false
+    }
+}
+
+/// The outbound side of one UDP association, carrying datagrams to any
+/// number of targets.
+pub trait UdpSession: Send + Sync {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a Socks5Target) -> DialFuture<'a, ()>;
+
+    /// Receives a datagram and the address it came from.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> DialFuture<'a, (usize, SocketAddr)>;
+}
+
+/// The dialers used for targets matching a pattern: `[USER@]DEST=UPSTREAM`,
+/// where the upstream is `direct`, `socks5://[USER:PASSWORD@]HOST:PORT`,
+/// `socks5h://...` to have the upstream resolve domains, or
+/// `http://[USER:PASSWORD@]HOST:PORT`.
+#[derive(Clone)]
+pub struct Route {
+    pub targets: TargetPattern,
+    /// Only sessions of this user take the route when set.
+    pub user: Option<String>,
+    pub tcp: Arc<dyn TcpDialer>,
+    /// `None` relays UDP directly.
+    pub udp: Option<Arc<dyn UdpDialer>>,
+}
+
+/// Connects to targets directly.
+pub struct Direct;
+
+/// Connects through an HTTP proxy with the CONNECT method.
+pub struct HttpConnect {
+    proxy: String,
+    /// The `Proxy-Authorization` header value.
+    authorization: Option<String>,
+}
+
+/// Connects through another SOCKS5 server, relaying UDP through it too.
+pub struct Socks5Upstream {
+    client: Socks5Client,
+    /// Whether domains are resolved here and sent as addresses.
+    resolve: bool,
+}
+
+/// A UDP session through an upstream, with domains resolved here.
+struct LocalDns {
+    session: Box<dyn UdpSession>,
+    hosts: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl Route {
+    /// The first route matching `target` for a session of `user`, if any.
+    pub fn find<'a>(
+        routes: &'a [Route],
+        target: &Socks5Target,
+        user: Option<&Identity>,
+    ) -> Option<&'a Route> {
+        routes.iter().find(|x| x.matches(target, user))
+    }
+
+    /// Whether the route applies to `target` for a session of `user`.
+    pub fn matches(&self, target: &Socks5Target, user: Option<&Identity>) -> bool {
+        let user_matches = match &self.user {
+            Some(x) => user.is_some_and(|user| user.user == *x),
+            None => true,
+        };
+        user_matches && self.targets.matches(target, None)
+    }
+}
+
+impl fmt::Debug for Route {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Route")
+            .field("targets", &self.targets)
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (targets, upstream) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid route: {s}!"))?;
+        let (user, targets) = match targets.split_once('@') {
+            Some((user, targets)) => (Some(user.to_owned()), targets),
+            None => (None, targets),
+        };
+        let targets = targets.parse()?;
+        if upstream == "direct" {
+            return Ok(Self {
+                targets,
+                user,
+                tcp: Arc::new(Direct),
+                udp: None,
+            });
+        }
+
+        let invalid = || format!("Invalid upstream: {upstream}!");
+        let (scheme, rest) = upstream.split_once("://").ok_or_else(invalid)?;
+        let (auth, proxy) = match rest.rsplit_once('@') {
+            Some((auth, proxy)) => (Some(StaticUsers::parse_user(auth)?), proxy),
+            None => (None, rest),
+        };
+        let proxy = proxy.trim_end_matches('/');
+        proxy.parse::<Socks5Target>()?;
+
+        Ok(match scheme {
+            "socks5" | "socks5h" => {
+                let upstream = Socks5Upstream::new(proxy, auth);
+                let upstream = Arc::new(match scheme {
+                    "socks5" => upstream.resolve_locally(),
+                    _ => upstream,
+                });
+                Self {
+                    targets,
+                    user,
+                    tcp: upstream.clone(),
+                    udp: Some(upstream),
+                }
+            }
+            "http" => Self {
+                targets,
+                user,
+                tcp: Arc::new(HttpConnect::new(proxy, auth)),
+                udp: None,
+            },
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+impl TcpDialer for Direct {
+    fn dial<'a>(&'a self, target: &'a Socks5Target) -> DialFuture<'a, TcpStream> {
+        Box::pin(async move {
+            match &target.0 {
+                Socks5Host::IpAddr(x) => TcpStream::connect((*x, target.1)).await,
+                Socks5Host::Domain(x) => TcpStream::connect((x.as_str(), target.1)).await,
+            }
+            .map_err(Error::Connect)
+        })
+    }
+
+    fn resolves_locally(&self) -> bool {
+        true
+    }
+}
+
+impl UdpDialer for Direct {
+    fn bind(&self) -> DialFuture<'_, Box<dyn UdpSession>> {
+        Box::pin(async { Ok(Box::new(Socks5UdpForwarder::bind()?.into_session()) as _) })
+    }
+
+    fn resolves_locally(&self) -> bool {
+        true
+    }
+}
+
+impl HttpConnect {
+    /// A dialer for the HTTP proxy at `HOST:PORT`.
+    pub fn new(proxy: &str, auth: Option<(String, String)>) -> Self {
+        Self {
+            proxy: proxy.to_owned(),
+            authorization: auth.map(|(user, password)| {
+                format!("Basic {}", base64(format!("{user}:{password}").as_bytes()))
+            }),
+        }
+    }
+}
+
+impl TcpDialer for HttpConnect {
+    fn dial<'a>(&'a self, target: &'a Socks5Target) -> DialFuture<'a, TcpStream> {
+        Box::pin(async move {
+            let mut stream = TcpStream::connect(self.proxy.as_str()).await?;
+            let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+            if let Some(x) = &self.authorization {
+                request.push_str(&format!("Proxy-Authorization: {x}\r\n"));
+            }
+            request.push_str("\r\n");
+            stream.write_all(request.as_bytes()).await?;
+
+            // Read the response head byte by byte, so that nothing the target
+            // sends after it is consumed.
+            let mut head = Vec::with_capacity(128);
+            while !head.ends_with(b"\r\n\r\n") {
+                if head.len() >= 8192 {
+                    return Err(Error::Upstream("HTTP proxy response too long!".into()));
+                }
+                head.push(stream.read_u8().await?);
+            }
+            let status = String::from_utf8_lossy(&head);
+            let status = status.lines().next().unwrap_or_default();
+            match status.split(' ').nth(1) {
+                Some(x) if x.starts_with('2') => Ok(stream),
+                _ => Err(Error::Upstream(format!("HTTP proxy refused: {status}!"))),
+            }
+        })
+    }
+}
+
+impl Socks5Upstream {
+    /// A dialer for the SOCKS5 server at `HOST:PORT`, which resolves
+    /// domains itself.
+    pub fn new(proxy: &str, auth: Option<(String, String)>) -> Self {
+        let client = Socks5Client::new(proxy);
+        Self {
+            client: match auth {
+                Some((user, password)) => client.with_auth(user, password),
+                None => client,
+            },
+            resolve: false,
+        }
+    }
+
+    /// Resolves domains here and sends the upstream addresses only.
+    pub fn resolve_locally(mut self) -> Self {
+        self.resolve = true;
+        self
+    }
+}
+
+impl TcpDialer for Socks5Upstream {
+    fn dial<'a>(&'a self, target: &'a Socks5Target) -> DialFuture<'a, TcpStream> {
+        Box::pin(async move {
+            let stream = match (&target.0, self.resolve) {
+                (Socks5Host::Domain(x), true) => {
+                    let ip = lookup_host(x).await.map_err(Error::Connect)?;
+                    let target = Socks5Target(Socks5Host::IpAddr(ip), target.1);
+                    self.client.connect(&target).await?
+                }
+                _ => self.client.connect(target).await?,
+            };
+            Ok(stream.into_inner())
+        })
+    }
+
+    fn resolves_locally(&self) -> bool {
+        self.resolve
+    }
+}
+
+impl UdpDialer for Socks5Upstream {
+    fn bind(&self) -> DialFuture<'_, Box<dyn UdpSession>> {
+        Box::pin(async move {
+            let session = Box::new(self.client.associate().await?);
+            Ok(match self.resolve {
+                true => Box::new(LocalDns {
+                    session,
+                    hosts: Mutex::default(),
+                }) as _,
+                false => session as _,
+            })
+        })
+    }
+
+    fn resolves_locally(&self) -> bool {
+        self.resolve
+    }
+}
+
+impl UdpSession for LocalDns {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a Socks5Target) -> DialFuture<'a, ()> {
+        Box::pin(async move {
+            let Socks5Host::Domain(host) = &target.0 else {
+                return self.session.send_to(buf, target).await;
+            };
+            let cached = self.hosts.lock().unwrap().get(host).copied();
+            let ip = match cached {
+                Some(x) => x,
+                None => {
+                    let ip = lookup_host(host).await.map_err(Error::Connect)?;
+                    self.hosts.lock().unwrap().insert(host.clone(), ip);
+                    ip
+                }
+            };
+            let target = Socks5Target(Socks5Host::IpAddr(ip), target.1);
+            self.session.send_to(buf, &target).await
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> DialFuture<'a, (usize, SocketAddr)> {
+        self.session.recv_from(buf)
+    }
+}
+
+/// The first address of `host`.
+async fn lookup_host(host: &str) -> io::Result<IpAddr> {
+    match tokio::net::lookup_host((host, 0)).await?.next() {
+        Some(x) => Ok(x.ip()),
+        None => Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("Could not resolve {host}!"),
+        )),
+    }
+}
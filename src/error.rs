@@ -1,31 +1,132 @@
 use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind};
 use std::{error, result};
 
+use crate::codec::CodecError;
+
 pub type Result<T> = result::Result<T, Error>;
 
 pub trait IntoError {}
 
 #[derive(Debug)]
 pub enum Error {
-    Boxed(Box<dyn error::Error + Send>),
+    /// A malformed SOCKS5 message.
+    Codec(CodecError),
+    /// A client breaking the protocol in some other way.
+    Protocol(String),
+    /// A domain that is not a valid internationalized domain name.
+    InvalidDomain(String),
+    /// A client refused by authentication or policy.
+    Denied(String),
+    /// The server is at its limit of concurrent sessions.
+    Busy(String),
+    /// Connecting to the target failed.
+    Connect(io::Error),
+    /// A SOCKS5 server refused a request with this reply code.
+    Reply(u8),
+    /// An upstream proxy failed in some other way.
+    Upstream(String),
+    Io(io::Error),
+    Boxed(Box<dyn error::Error + Send + Sync>),
     String(String),
 }
 
+impl Error {
+    /// The RFC 1928 reply code telling a client about the error.
+    pub fn reply_code(&self) -> u8 {
+        match self {
+            Self::Codec(CodecError::Command(_)) => 0x07,
+            Self::Codec(CodecError::AddressType(_) | CodecError::Domain) => 0x08,
+            Self::Denied(_) => 0x02,
+            Self::InvalidDomain(_) => 0x04,
+            Self::Connect(e) => match e.kind() {
+                ErrorKind::NetworkUnreachable => 0x03,
+                ErrorKind::ConnectionRefused => 0x05,
+                // Including names that do not resolve.
+                _ => 0x04,
+            },
+            Self::Reply(x) => *x,
+            _ => 0x01,
+        }
+    }
+
+    /// What kind of error this is, as logged.
+    pub fn category(&self) -> &'static str {
+        match self {
+            Self::Codec(_) | Self::Protocol(_) | Self::InvalidDomain(_) => "protocol",
+            Self::Denied(_) => "denied",
+            Self::Busy(_) => "busy",
+            Self::Connect(_) => "connect",
+            Self::Reply(_) | Self::Upstream(_) => "upstream",
+            Self::Io(_) => "io",
+            Self::Boxed(_) | Self::String(_) => "internal",
+        }
+    }
+}
+
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
-            Self::String(s) => s.fmt(f),
+            Self::Codec(e) => e.fmt(f),
+            Self::InvalidDomain(x) => write!(f, "Invalid domain: {x:?}!"),
+            Self::Connect(e) | Self::Io(e) => e.fmt(f),
+            Self::Reply(x) => write!(f, "SOCKS5 request failed: {}!", reply_message(*x)),
             Self::Boxed(e) => e.fmt(f),
+            Self::Protocol(s)
+            | Self::Denied(s)
+            | Self::Busy(s)
+            | Self::Upstream(s)
+            | Self::String(s) => s.fmt(f),
         }
     }
 }
 
-impl<E: 'static + error::Error + Send + IntoError> From<E> for Error {
+/// Wrapped errors are displayed as they are, so their source is the one of
+/// the wrapped error.
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Codec(e) => e.source(),
+            Self::Connect(e) | Self::Io(e) => e.source(),
+            Self::Boxed(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+/// Describes a SOCKS5 reply code (RFC 1928, section 6).
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        0x01 => "general SOCKS server failure",
+        0x02 => "connection not allowed by ruleset",
+        0x03 => "network unreachable",
+        0x04 => "host unreachable",
+        0x05 => "connection refused",
+        0x06 => "TTL expired",
+        0x07 => "command not supported",
+        0x08 => "address type not supported",
+        _ => "unassigned reply code",
+    }
+}
+
+impl<E: 'static + error::Error + Send + Sync + IntoError> From<E> for Error {
     fn from(e: E) -> Self {
         Self::Boxed(Box::new(e))
     }
 }
 
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<CodecError> for Error {
+    fn from(e: CodecError) -> Self {
+        Error::Codec(e)
+    }
+}
+
 impl From<String> for Error {
     fn from(s: String) -> Self {
         Error::String(s)
@@ -38,4 +139,13 @@ impl From<&str> for Error {
     }
 }
 
-impl IntoError for std::io::Error {}
+impl IntoError for tokio::task::JoinError {}
+
+#[cfg(feature = "tls")]
+impl IntoError for tokio_rustls::rustls::Error {}
+
+#[cfg(feature = "tls")]
+impl IntoError for tokio_rustls::rustls::pki_types::pem::Error {}
+
+#[cfg(feature = "tls")]
+impl IntoError for tokio_rustls::rustls::server::VerifierBuilderError {}
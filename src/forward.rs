@@ -0,0 +1,83 @@
+use std::str::FromStr;
+
+use super::*;
+use crate::udp::Flows;
+
+/// A static tunnel from a local port to a fixed target, like `ssh -L`:
+/// `[tcp/|udp/]LISTEN=HOST:PORT`.
+#[derive(Clone, Debug)]
+pub struct Forward {
+    pub udp: bool,
+    pub listen: SocketAddr,
+    pub target: Socks5Target,
+}
+
+impl FromStr for Forward {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (udp, rest) = match s.split_once('/') {
+            Some(("tcp", rest)) => (false, rest),
+            Some(("udp", rest)) => (true, rest),
+            _ => (false, s),
+        };
+        let (listen, target) = rest
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid forward: {s}!"))?;
+        Ok(Self {
+            udp,
+            listen: listen
+                .parse()
+                .map_err(|_| format!("Invalid listen address: {listen}!"))?,
+            target: target.parse()?,
+        })
+    }
+}
+
+impl Display for Forward {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let protocol = if self.udp { "udp" } else { "tcp" };
+        write!(f, "{protocol}/{}={}", self.listen, self.target)
+    }
+}
+
+/// Relays datagrams arriving on `socket` to `target`, one flow per client,
+/// through the routes and rules like tunneled connections. The socket
+/// counts as a listener of its own for bandwidth limits.
+pub async fn serve_udp(
+    socket: UdpSocket,
+    target: Socks5Target,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    sessions: Arc<Registry>,
+) -> Result<()> {
+    let socket = Arc::new(socket);
+    let local_addr = socket.local_addr()?;
+    let flows = Flows::new(config, metrics.clone(), sessions);
+    let mut buf = vec![0; 65536];
+
+    loop {
+        let (len, client) = socket.recv_from(&mut buf).await?;
+        let key = (client, local_addr);
+        let flow = match flows.get(&key) {
+            Some(x) => x,
+            None => {
+                let opened = async {
+                    let session = flows.session(client, target.clone(), "forward")?;
+                    let upstream = flows.dial(&session.0, &target).await?;
+                    Ok::<_, Error>((session, upstream))
+                };
+                let (session, upstream) = match opened.await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        debug!("{client} => {target} (UDP): {e}");
+                        Metrics::inc(&metrics.udp_drops);
+                        continue;
+                    }
+                };
+                flows.spawn(session, upstream, (socket.clone(), Some(client)), key)
+            }
+        };
+        flow.send(&buf[..len]).await;
+    }
+}
@@ -0,0 +1,155 @@
+use std::future::Future;
+
+use super::*;
+use crate::server::ACCEPT_BACKOFF;
+
+const MAX_REQUEST_SIZE: usize = 8192;
+
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    /// The `Host` header, if sent.
+    pub host: Option<String>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Request {
+    /// Returns the percent-decoded value of `key` in the query string.
+    pub fn query(&self, key: &str) -> Option<String> {
+        self.query
+            .as_deref()?
+            .split('&')
+            .filter_map(|x| x.split_once('='))
+            .find_map(|(k, v)| (k == key).then(|| percent_decode(v)))
+    }
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Self::new(status, "text/plain; charset=utf-8", body.into())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Serves a minimal HTTP/1.0 style endpoint: one request per connection,
+/// no request bodies, and the connection is closed after the response.
+/// Accept errors are logged and waited out, as by the SOCKS listeners.
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = Response> + Send,
+{
+    loop {
+        let stream = match listener.accept().await {
+            Ok((x, _)) => x,
+            Err(e) => {
+                log!(Level::Error, "Failed to accept an HTTP connection: {e}");
+                tokio::time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let _ = handle(stream, handler).await;
+        });
+    }
+}
+
+async fn handle<F, Fut>(mut stream: TcpStream, handler: F) -> Result<()>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Response>,
+{
+    let mut buf = Vec::with_capacity(1024);
+    while !buf.ends_with(b"\r\n\r\n") && !buf.ends_with(b"\n\n") {
+        if buf.len() >= MAX_REQUEST_SIZE {
+            return Err("HTTP request too large!".into());
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err("Incomplete HTTP request!".into());
+        }
+    }
+
+    let head = String::from_utf8_lossy(&buf);
+    let mut lines = head.lines();
+    let line = lines.next().unwrap_or_default();
+    let host = lines
+        .filter_map(|x| x.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_owned());
+    let mut parts = line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => {
+            let (path, query) = match target.split_once('?') {
+                Some((path, query)) => (path, Some(query.to_owned())),
+                None => (target, None),
+            };
+            handler(Request {
+                method: method.to_owned(),
+                path: path.to_owned(),
+                query,
+                host,
+            })
+            .await
+        }
+        _ => Response::text(400, "Bad request\n"),
+    };
+
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(x) = iter.next() {
+        match x {
+            b'%' => {
+                let hex = [iter.next(), iter.next()];
+                match hex.map(|x| x.and_then(|x| (x as char).to_digit(16))) {
+                    [Some(h), Some(l)] => bytes.push((h * 16 + l) as u8),
+                    _ => {
+                        bytes.push(b'%');
+                        bytes.extend(hex.into_iter().flatten());
+                    }
+                }
+            }
+            b'+' => bytes.push(b' '),
+            x => bytes.push(x),
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
@@ -0,0 +1,112 @@
+//! A lightweight SOCKS5 proxy server, usable as a library.
+//!
+//! ```no_run
+//! # async fn run() -> sock5s::Result<()> {
+//! let server = sock5s::Server::builder()
+//!     .listen("127.0.0.1:1080".parse()?)
+//!     .build()
+//!     .await?;
+//! let handle = server.handle();
+//! tokio::spawn(server.run());
+//! // ...
+//! handle.shutdown();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::io::{self, ErrorKind, IoSlice};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
+use tokio_stream::{Stream, StreamExt, StreamMap};
+
+#[cfg(target_family = "unix")]
+pub use self::activation::detach_stdio;
+#[cfg(target_os = "linux")]
+use self::splice::{Pipe, splice_one};
+#[cfg(feature = "tls")]
+pub use self::tls::{CertIdentity, TlsOptions};
+#[cfg(target_os = "linux")]
+pub use self::transparent::TransparentMode;
+#[cfg(target_family = "unix")]
+pub use self::unix::UnixOptions;
+#[cfg(target_family = "unix")]
+use self::util::set_rlimit_nofile;
+use self::{
+    acceptor::Socks5Acceptor,
+    codec::{
+        CodecError, Command, Decode, Encode, Greeting, MethodSelection, NO_ACCEPTABLE_METHODS,
+        Reply, Request, UdpHeader, UserPassAuth,
+    },
+    listener::Socks5Listener,
+    log::{Level, debug, info, log},
+    metrics::{Metrics, Reject},
+    session::{Registry, Session},
+    shaper::{Buckets, Limiter},
+    stream::Socks5Stream,
+    udp::Socks5UdpForwarder,
+    util::{Split, base64, read_message, shutdown_write, write_message},
+};
+pub use self::{
+    auth::{AuthFuture, AuthStream, Authenticator, FileUsers, Identity, NoAuth, StaticUsers},
+    client::{Socks5Bind, Socks5Client, Socks5ClientStream, Socks5UdpSocket},
+    config::Config,
+    dialer::{
+        DialFuture, Direct, HttpConnect, Route, Socks5Upstream, TcpDialer, UdpDialer, UdpSession,
+    },
+    error::{Error, Result},
+    forward::Forward,
+    limits::{Admission, ConnectionPermit, Permit, SessionLimits},
+    listener::ListenAddr,
+    proxy_protocol::{ProxyMode, ProxyRule},
+    rules::{Action, Principal, Rule, RuleSet},
+    server::{Server, ServerBuilder, ServerHandle},
+    shaper::{Rate, RateLimits, Shaper},
+    stream::Socks5Peer,
+    target::{Socks5Host, Socks5Target, TargetPattern},
+    util::Cidr,
+};
+
+mod acceptor;
+#[cfg(target_family = "unix")]
+mod activation;
+mod admin;
+mod auth;
+mod client;
+pub mod codec;
+mod config;
+mod dialer;
+mod error;
+mod forward;
+mod http;
+mod limits;
+mod listener;
+pub mod log;
+mod metrics;
+mod proxy_protocol;
+mod rules;
+mod server;
+mod session;
+mod shaper;
+#[cfg(target_os = "linux")]
+mod splice;
+mod stream;
+mod target;
+mod tcp;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(target_os = "linux")]
+mod transparent;
+mod udp;
+#[cfg(target_family = "unix")]
+mod unix;
+mod util;
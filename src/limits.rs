@@ -0,0 +1,260 @@
+use std::sync::Mutex;
+
+use super::*;
+
+/// Maximums on concurrent connections and sessions. A connection counts
+/// from the moment it is accepted, a session only once its request has
+/// been read.
+#[derive(Clone, Debug, Default)]
+pub struct SessionLimits {
+    /// Connections of all clients together, including those negotiating.
+    pub connections: Option<usize>,
+    /// Sessions of all clients together.
+    pub sessions: Option<usize>,
+    /// Sessions from each client IP address.
+    pub client: Option<usize>,
+    /// Sessions of each user.
+    pub user: Option<usize>,
+    /// UDP associations, and UDP flows of tunnels and transparent mode, from
+    /// each client IP address.
+    pub udp_client: Option<usize>,
+}
+
+/// Enforces [`SessionLimits`] by counting the sessions admitted.
+pub struct Admission {
+    limits: SessionLimits,
+    counts: Mutex<Counts>,
+}
+
+#[derive(Default)]
+struct Counts {
+    connections: usize,
+    sessions: usize,
+    clients: HashMap<IpAddr, usize>,
+    users: HashMap<String, usize>,
+    udp_clients: HashMap<IpAddr, usize>,
+}
+
+/// A session admitted by an [`Admission`], which counts until dropped.
+pub struct Permit {
+    admission: Arc<Admission>,
+    client: Option<IpAddr>,
+    user: Option<String>,
+    udp: bool,
+}
+
+/// A connection admitted by an [`Admission`], which counts until dropped.
+pub struct ConnectionPermit(Arc<Admission>);
+
+impl Admission {
+    pub fn new(limits: SessionLimits) -> Self {
+        Self {
+            limits,
+            counts: Mutex::default(),
+        }
+    }
+
+    /// Admits a connection just accepted, unless there are too many.
+    pub fn connect(self: &Arc<Self>) -> Option<ConnectionPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        if self
+            .limits
+            .connections
+            .is_some_and(|x| counts.connections >= x)
+        {
+            return None;
+        }
+        counts.connections += 1;
+        Some(ConnectionPermit(self.clone()))
+    }
+
+    /// Admits a session, unless that would exceed a limit. Limits on a
+    /// client or user deny it, the limit on all sessions means the server
+    /// is busy.
+    pub fn admit(self: &Arc<Self>, session: &Session, udp: bool) -> Result<Permit> {
+        let limits = &self.limits;
+        let client = match session.client() {
+            Socks5Peer::Inet(x) => Some(x.ip()),
+            _ => None,
+        };
+        let user = session.user.get().map(|x| &x.user);
+        let mut counts = self.counts.lock().unwrap();
+
+        if let (Some(max), Some(ip)) = (limits.client, &client)
+            && held(&counts.clients, ip) >= max
+        {
+            return Err(Error::Denied(format!("Too many sessions from {ip}!")));
+        }
+        if let (Some(max), Some(user)) = (limits.user, user)
+            && held(&counts.users, user) >= max
+        {
+            return Err(Error::Denied(format!("Too many sessions of {user}!")));
+        }
+        if let (Some(max), Some(ip), true) = (limits.udp_client, &client, udp)
+            && held(&counts.udp_clients, ip) >= max
+        {
+            return Err(Error::Denied(format!(
+                "Too many UDP associations from {ip}!"
+            )));
+        }
+        if limits.sessions.is_some_and(|x| counts.sessions >= x) {
+            return Err(Error::Busy("Too many sessions!".into()));
+        }
+
+        // Only what is limited is counted, so that the maps stay small.
+        counts.sessions += 1;
+        let client = client.filter(|_| limits.client.is_some() || limits.udp_client.is_some());
+        if let Some(ip) = client {
+            if limits.client.is_some() {
+                *counts.clients.entry(ip).or_default() += 1;
+            }
+            if udp && limits.udp_client.is_some() {
+                *counts.udp_clients.entry(ip).or_default() += 1;
+            }
+        }
+        let user = user.filter(|_| limits.user.is_some()).cloned();
+        if let Some(user) = &user {
+            *counts.users.entry(user.clone()).or_default() += 1;
+        }
+
+        Ok(Permit {
+            admission: self.clone(),
+            client,
+            user,
+            udp,
+        })
+    }
+}
+
+fn held<K: Eq + std::hash::Hash>(map: &HashMap<K, usize>, key: &K) -> usize {
+    map.get(key).copied().unwrap_or(0)
+}
+
+/// Counts one session less for `key`, forgetting it at zero.
+fn release<K: Eq + std::hash::Hash>(map: &mut HashMap<K, usize>, key: &K) {
+    if let Some(x) = map.get_mut(key) {
+        *x -= 1;
+        if *x == 0 {
+            map.remove(key);
+        }
+    }
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        self.0.counts.lock().unwrap().connections -= 1;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.admission.counts.lock().unwrap();
+        counts.sessions -= 1;
+        if let Some(ip) = &self.client {
+            release(&mut counts.clients, ip);
+            if self.udp {
+                release(&mut counts.udp_clients, ip);
+            }
+        }
+        if let Some(user) = &self.user {
+            release(&mut counts.users, user);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(client: &str, user: Option<&str>) -> Session {
+        let client = SocketAddr::new(client.parse().unwrap(), 40000);
+        let session = Session::new(Socks5Peer::Inet(client), Arc::default());
+        if let Some(user) = user {
+            let _ = session.user.set(Identity { user: user.into() });
+        }
+        session
+    }
+
+    fn admission(limits: SessionLimits) -> Arc<Admission> {
+        Arc::new(Admission::new(limits))
+    }
+
+    #[test]
+    fn per_client() {
+        let admission = admission(SessionLimits {
+            client: Some(2),
+            ..Default::default()
+        });
+        let a = session("192.0.2.1", None);
+        let first = admission.admit(&a, false).unwrap();
+        let _second = admission.admit(&a, true).unwrap();
+        assert!(matches!(admission.admit(&a, false), Err(Error::Denied(_))));
+        assert!(admission.admit(&session("192.0.2.2", None), false).is_ok());
+
+        drop(first);
+        assert!(admission.admit(&a, false).is_ok());
+    }
+
+    #[test]
+    fn per_user() {
+        let admission = admission(SessionLimits {
+            user: Some(1),
+            ..Default::default()
+        });
+        let alice = admission
+            .admit(&session("192.0.2.1", Some("alice")), false)
+            .unwrap();
+        let again = admission.admit(&session("192.0.2.2", Some("alice")), false);
+        assert!(matches!(again, Err(Error::Denied(_))));
+        assert!(
+            admission
+                .admit(&session("192.0.2.1", Some("bob")), false)
+                .is_ok()
+        );
+        // Anonymous sessions have no user to count.
+        assert!(admission.admit(&session("192.0.2.1", None), false).is_ok());
+        assert!(admission.admit(&session("192.0.2.1", None), false).is_ok());
+
+        drop(alice);
+        assert!(
+            admission
+                .admit(&session("192.0.2.2", Some("alice")), false)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn udp_per_client() {
+        let admission = admission(SessionLimits {
+            udp_client: Some(1),
+            ..Default::default()
+        });
+        let a = session("192.0.2.1", None);
+        let udp = admission.admit(&a, true).unwrap();
+        assert!(matches!(admission.admit(&a, true), Err(Error::Denied(_))));
+        // Only UDP sessions count against it.
+        assert!(admission.admit(&a, false).is_ok());
+
+        drop(udp);
+        assert!(admission.admit(&a, true).is_ok());
+    }
+
+    #[test]
+    fn sessions_and_connections() {
+        let admission = admission(SessionLimits {
+            connections: Some(1),
+            sessions: Some(1),
+            ..Default::default()
+        });
+        let permit = admission.admit(&session("192.0.2.1", None), false).unwrap();
+        let busy = admission.admit(&session("192.0.2.2", None), false);
+        assert!(matches!(busy, Err(Error::Busy(_))));
+        drop(permit);
+        assert!(admission.admit(&session("192.0.2.2", None), false).is_ok());
+
+        let connection = admission.connect().unwrap();
+        assert!(admission.connect().is_none());
+        drop(connection);
+        assert!(admission.connect().is_some());
+    }
+}
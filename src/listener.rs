@@ -1,25 +1,210 @@
+#[cfg(target_family = "unix")]
+use std::os::fd::{OwnedFd, RawFd};
+#[cfg(target_family = "unix")]
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+#[cfg(target_family = "unix")]
+use tokio::net::UnixListener;
+
 use super::*;
 
 pub struct Socks5Listener {
-    listener: TcpListener,
+    listener: Listener,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    /// The bandwidth limit shared by the clients of this listener.
+    buckets: Option<Buckets>,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(target_family = "unix")]
+    Unix(UnixListener),
+}
+
+/// The address given to `--listen`: `HOST:PORT`, `unix:PATH`, `systemd`
+/// or `inetd`.
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(target_family = "unix")]
+    Unix(PathBuf),
+    /// The sockets passed by systemd socket activation.
+    #[cfg(target_family = "unix")]
+    Systemd,
+    /// A single client connection passed as stdin.
+    #[cfg(target_family = "unix")]
+    Inetd,
 }
 
 impl Socks5Listener {
-    pub async fn listen<A: ToSocketAddrs>(addr: A) -> Result<Self> {
-        Ok(Self {
-            listener: TcpListener::bind(addr).await?,
-        })
+    pub async fn listen<A: ToSocketAddrs>(
+        addr: A,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        Self::from_listener(TcpListener::bind(addr).await?, config, metrics)
+    }
+
+    pub fn from_listener(
+        listener: TcpListener,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        #[cfg(target_os = "linux")]
+        if config.transparent == Some(TransparentMode::Tproxy) {
+            transparent::set_transparent(&listener, listener.local_addr()?.is_ipv6())?;
+        }
+        Ok(Self::new(Listener::Tcp(listener), config, metrics))
+    }
+
+    #[cfg(target_family = "unix")]
+    pub fn listen_unix(
+        path: &Path,
+        options: &UnixOptions,
+        config: Arc<Config>,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self> {
+        check_unix(&config)?;
+        Ok(Self::new(
+            Listener::Unix(options.bind(path)?),
+            config,
+            metrics,
+        ))
+    }
+
+    /// Takes over an inherited listening socket, e.g. one passed by systemd.
+    #[cfg(target_family = "unix")]
+    pub fn from_fd(fd: RawFd, config: Arc<Config>, metrics: Arc<Metrics>) -> Result<Self> {
+        let (socket, local_addr) = activation::inherited_socket(fd)?;
+        let listener = if local_addr.is_unix() {
+            check_unix(&config)?;
+            let listener = std::os::unix::net::UnixListener::from(OwnedFd::from(socket));
+            Listener::Unix(UnixListener::from_std(listener)?)
+        } else {
+            Listener::Tcp(TcpListener::from_std(socket.into())?)
+        };
+        Ok(Self::new(listener, config, metrics))
+    }
+
+    fn new(listener: Listener, config: Arc<Config>, metrics: Arc<Metrics>) -> Self {
+        let buckets = config.shaper.as_ref().and_then(|x| x.listener());
+        Self {
+            listener,
+            config,
+            metrics,
+            buckets,
+        }
+    }
+
+    /// The address of a TCP listener.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match &self.listener {
+            Listener::Tcp(x) => x.local_addr(),
+            #[cfg(target_family = "unix")]
+            Listener::Unix(_) => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Not an IP listener!",
+            )),
+        }
     }
 }
 
+#[cfg(target_family = "unix")]
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn check_unix(config: &Config) -> Result<()> {
+    #[cfg(feature = "tls")]
+    if config.tls.is_some() {
+        return Err("TLS is not supported on Unix socket listeners!".into());
+    }
+    Ok(())
+}
+
 impl Stream for Socks5Listener {
-    type Item = Result<(Socks5Acceptor, SocketAddr)>;
+    type Item = Result<(Socks5Acceptor, Socks5Peer)>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let (stream, client) = match self.listener.poll_accept(cx) {
-            Poll::Ready(t) => t,
-            Poll::Pending => return Poll::Pending,
-        }?;
-        Poll::Ready(Some(Ok((Socks5Acceptor::from(stream), client))))
+        loop {
+            let (stream, client) = match &self.listener {
+                Listener::Tcp(x) => match x.poll_accept(cx) {
+                    Poll::Ready(t) => {
+                        let (stream, client) = t?;
+                        (Socks5Stream::from(stream), Socks5Peer::Inet(client))
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+                #[cfg(target_family = "unix")]
+                Listener::Unix(x) => match x.poll_accept(cx) {
+                    Poll::Ready(t) => {
+                        let (stream, _) = t?;
+                        // A client that is already gone must not stop the
+                        // listener.
+                        match Socks5Peer::of_unix(&stream) {
+                            Ok(client) => (Socks5Stream::from(stream), client),
+                            Err(e) => {
+                                log!(Level::Warn, "Unix client without credentials: {e}");
+                                continue;
+                            }
+                        }
+                    }
+                    Poll::Pending => return Poll::Pending,
+                },
+            };
+            Metrics::inc(&self.metrics.connections);
+
+            // Closing at once is all a connection over the limit costs.
+            let connection = match &self.config.admission {
+                Some(x) => match x.connect() {
+                    Some(x) => Some(x),
+                    None => {
+                        self.metrics.reject(Reject::Limit);
+                        debug!("{client} =! Too many connections!");
+                        continue;
+                    }
+                },
+                None => None,
+            };
+            let mut acceptor =
+                Socks5Acceptor::new(stream, client, self.config.clone(), self.metrics.clone());
+            acceptor.buckets = self.buckets.clone();
+            acceptor.connection = connection;
+            return Poll::Ready(Some(Ok((acceptor, client))));
+        }
+    }
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        #[cfg(target_family = "unix")]
+        match s {
+            "systemd" => return Ok(ListenAddr::Systemd),
+            "inetd" => return Ok(ListenAddr::Inetd),
+            _ => {
+                if let Some(path) = s.strip_prefix("unix:") {
+                    return Ok(ListenAddr::Unix(path.into()));
+                }
+            }
+        }
+
+        s.parse()
+            .map(ListenAddr::Tcp)
+            .map_err(|_| format!("Invalid listen address: {s}!"))
+    }
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(x) => x.fmt(f),
+            #[cfg(target_family = "unix")]
+            ListenAddr::Unix(x) => write!(f, "unix:{}", x.display()),
+            #[cfg(target_family = "unix")]
+            ListenAddr::Systemd => f.write_str("systemd"),
+            #[cfg(target_family = "unix")]
+            ListenAddr::Inetd => f.write_str("inetd"),
+        }
     }
 }
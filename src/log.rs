@@ -0,0 +1,326 @@
+#[cfg(target_family = "unix")]
+use std::ffi::CString;
+use std::fmt::{self, Display, Write};
+use std::io::Write as _;
+use std::str::FromStr;
+use std::sync::OnceLock;
+#[cfg(target_family = "unix")]
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::*;
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// Whether records go to syslog rather than stderr.
+#[cfg(target_family = "unix")]
+static SYSLOG: AtomicBool = AtomicBool::new(false);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Debug)]
+pub struct Logger {
+    level: Level,
+    format: Format,
+}
+
+/// A field value in a log record, kept apart from strings so that numbers
+/// stay numbers in the JSON output.
+pub enum Value {
+    Number(String),
+    String(String),
+    Null,
+}
+
+pub trait ToValue {
+    fn to_value(&self) -> Value;
+}
+
+impl Level {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => return Err(format!("Invalid log level: {s}!")),
+        })
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "text" => Format::Text,
+            "json" => Format::Json,
+            _ => return Err(format!("Invalid log format: {s}!")),
+        })
+    }
+}
+
+macro_rules! impl_number_value {
+    ($($t:ty),+) => {$(
+        impl ToValue for $t {
+            fn to_value(&self) -> Value {
+                Value::Number(self.to_string())
+            }
+        }
+    )+};
+}
+
+macro_rules! impl_string_value {
+    ($($t:ty),+) => {$(
+        impl ToValue for $t {
+            fn to_value(&self) -> Value {
+                Value::String(self.to_string())
+            }
+        }
+    )+};
+}
+
+impl_number_value!(u8, u16, u32, u64, usize, i64, f64);
+impl_string_value!(
+    Identity,
+    str,
+    String,
+    SocketAddr,
+    IpAddr,
+    Socks5Peer,
+    Socks5Target,
+    Error
+);
+
+impl<T: ToValue + ?Sized> ToValue for &T {
+    fn to_value(&self) -> Value {
+        (*self).to_value()
+    }
+}
+
+impl<T: ToValue> ToValue for Option<T> {
+    fn to_value(&self) -> Value {
+        match self {
+            Some(x) => x.to_value(),
+            None => Value::Null,
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Number(x) | Value::String(x) => x.fmt(f),
+            Value::Null => f.write_str("-"),
+        }
+    }
+}
+
+/// Installs the global logger. Only the first call has any effect.
+pub fn init(level: Level, format: Format) {
+    let _ = LOGGER.set(Logger { level, format });
+}
+
+/// Sends records to syslog (or the journal) instead of stderr from now on.
+#[cfg(target_family = "unix")]
+pub fn use_syslog() {
+    unsafe { libc::openlog(c"sock5s".as_ptr(), libc::LOG_PID, libc::LOG_DAEMON) };
+    SYSLOG.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= LOGGER.get().map_or(Level::Info, |x| x.level)
+}
+
+pub fn record(level: Level, fields: &[(&str, Value)], args: fmt::Arguments<'_>) {
+    if !enabled(level) {
+        return;
+    }
+
+    let format = LOGGER.get().map_or(Format::Text, |x| x.format);
+    let mut line = String::with_capacity(128);
+    let _ = match format {
+        Format::Text => write_text(&mut line, level, fields, args),
+        Format::Json => write_json(&mut line, level, fields, args),
+    };
+
+    #[cfg(target_family = "unix")]
+    if SYSLOG.load(Ordering::Relaxed) {
+        let priority = match level {
+            Level::Error => libc::LOG_ERR,
+            Level::Warn => libc::LOG_WARNING,
+            Level::Info => libc::LOG_INFO,
+            Level::Debug | Level::Trace => libc::LOG_DEBUG,
+        };
+        if let Ok(line) = CString::new(line) {
+            unsafe { libc::syslog(priority, c"%s".as_ptr(), line.as_ptr()) };
+        }
+        return;
+    }
+
+    line.push('\n');
+    let _ = io::stderr().lock().write_all(line.as_bytes());
+}
+
+fn write_text(
+    line: &mut String,
+    level: Level,
+    fields: &[(&str, Value)],
+    args: fmt::Arguments<'_>,
+) -> fmt::Result {
+    write!(
+        line,
+        "{} {:5}",
+        Timestamp::now(),
+        level.as_str().to_uppercase()
+    )?;
+    if let Some((_, conn)) = fields.iter().find(|(k, _)| *k == "conn") {
+        write!(line, " [#{conn}]")?;
+    }
+    write!(line, " {args}")?;
+    for (key, value) in fields.iter().filter(|(k, _)| *k != "conn") {
+        write!(line, " {key}={value}")?;
+    }
+    Ok(())
+}
+
+fn write_json(
+    line: &mut String,
+    level: Level,
+    fields: &[(&str, Value)],
+    args: fmt::Arguments<'_>,
+) -> fmt::Result {
+    write!(
+        line,
+        "{{\"ts\":\"{}\",\"level\":\"{}\"",
+        Timestamp::now(),
+        level.as_str()
+    )?;
+    for (key, value) in fields {
+        line.push(',');
+        write_json_string(line, key)?;
+        line.push(':');
+        match value {
+            Value::Number(x) => line.push_str(x),
+            Value::String(x) => write_json_string(line, x)?,
+            Value::Null => line.push_str("null"),
+        }
+    }
+    line.push_str(",\"msg\":");
+    write_json_string(line, &args.to_string())?;
+    line.push('}');
+    Ok(())
+}
+
+pub fn write_json_string(line: &mut String, s: &str) -> fmt::Result {
+    line.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => line.push_str("\\\""),
+            '\\' => line.push_str("\\\\"),
+            '\n' => line.push_str("\\n"),
+            '\r' => line.push_str("\\r"),
+            '\t' => line.push_str("\\t"),
+            c if c < ' ' => write!(line, "\\u{:04x}", c as u32)?,
+            c => line.push(c),
+        }
+    }
+    line.push('"');
+    Ok(())
+}
+
+/// RFC 3339 UTC timestamp with millisecond precision.
+pub struct Timestamp(pub SystemTime);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Self(SystemTime::now())
+    }
+}
+
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let since_epoch = self.0.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs() as i64;
+        let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+        // Howard Hinnant's civil_from_days.
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        write!(
+            f,
+            "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            since_epoch.subsec_millis()
+        )
+    }
+}
+
+/// Logs a record at the given level. Structured fields may precede the
+/// message, separated from it by a semicolon:
+///
+/// `log!(Level::Info, conn = id, target = target; "Connected.")`
+macro_rules! log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::record(
+                $level,
+                &[$((stringify!($key), $crate::log::ToValue::to_value(&$value))),+],
+                format_args!($($arg)+),
+            )
+        }
+    };
+    ($level:expr, $($arg:tt)+) => {
+        if $crate::log::enabled($level) {
+            $crate::log::record($level, &[], format_args!($($arg)+))
+        }
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+pub(crate) use {debug, info, log};
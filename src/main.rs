@@ -1,37 +1,22 @@
-use std::collections::{HashMap, HashSet};
-use std::convert::TryFrom;
-use std::fmt::{self, Display, Formatter};
-use std::io::{ErrorKind, IoSlice};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::pin::Pin;
-use std::task::{Context, Poll};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::Parser;
 use indoc::indoc;
-use socket2::{Domain, Protocol, Socket, Type};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, ToSocketAddrs, UdpSocket};
-use tokio_stream::{Stream, StreamExt};
-
+#[cfg(target_os = "linux")]
+use sock5s::TransparentMode;
 #[cfg(target_family = "unix")]
-use self::util::set_rlimit_nofile;
-use self::{
-    acceptor::Socks5Acceptor,
-    error::{Error, Result},
-    listener::Socks5Listener,
-    target::{Socks5Host, Socks5Target},
-    util::{IntoResult, PutSocks5Addr, Split},
+use sock5s::UnixOptions;
+use sock5s::log::{self, Level};
+use sock5s::{
+    Admission, Authenticator, Cidr, Config, FileUsers, Forward, ListenAddr, ProxyMode, ProxyRule,
+    Rate, RateLimits, Result, Route, Rule, Server, SessionLimits, Shaper, StaticUsers,
 };
-
-pub type Socks5Stream = TcpStream;
-
-mod acceptor;
-mod error;
-mod listener;
-mod target;
-mod tcp;
-mod udp;
-mod util;
+#[cfg(feature = "tls")]
+use sock5s::{CertIdentity, TlsOptions};
 
 #[derive(Parser, Debug)]
 #[command(
@@ -52,30 +37,388 @@ struct Cli {
     #[arg(
         short = 'l',
         long = "listen",
-        value_name = "HOST:PORT",
-        help = "Listen address",
+        value_name = "ADDRESS",
+        help = "Listen address: HOST:PORT, unix:PATH, systemd (socket activation) or inetd (stdin)",
         required = true
     )]
-    listen: SocketAddr,
+    listen: ListenAddr,
+    #[cfg(target_family = "unix")]
+    #[arg(
+        long = "unix-mode",
+        value_name = "MODE",
+        value_parser = UnixOptions::parse_mode,
+        help = "Octal permissions of the Unix socket file, e.g. 660"
+    )]
+    unix_mode: Option<u32>,
+    #[cfg(target_family = "unix")]
+    #[arg(
+        long = "unix-owner",
+        value_name = "USER[:GROUP]",
+        value_parser = UnixOptions::parse_owner,
+        help = "Owner of the Unix socket file"
+    )]
+    unix_owner: Option<(Option<u32>, Option<u32>)>,
+    #[arg(
+        long = "user",
+        value_name = "USER:PASSWORD",
+        value_parser = StaticUsers::parse_user,
+        help = "Require username/password authentication with this user (may be repeated)"
+    )]
+    users: Vec<(String, String)>,
+    #[arg(
+        long = "users-file",
+        value_name = "FILE",
+        conflicts_with = "users",
+        help = "Require username/password authentication with the USER:PASSWORD lines of this file"
+    )]
+    users_file: Option<PathBuf>,
+    #[arg(
+        long = "route",
+        value_name = "[USER@]DEST=UPSTREAM",
+        help = "Dial targets matching DEST (CIDR, domain or *) through UPSTREAM: direct, socks5://[USER:PASSWORD@]HOST:PORT (socks5h:// to resolve domains upstream) or http://[USER:PASSWORD@]HOST:PORT, only for sessions of USER if given (may be repeated)"
+    )]
+    routes: Vec<Route>,
+    #[arg(
+        long = "rule",
+        value_name = "allow=[USER@]DEST|deny=[USER@]DEST",
+        help = "Allow or deny targets matching DEST (CIDR, domain or *), only for sessions of USER if given (unix:USER[:GROUP] for Unix socket clients by credentials), the first matching rule deciding; others are allowed (may be repeated)"
+    )]
+    rules: Vec<Rule>,
+    #[arg(
+        long = "half-close-timeout",
+        value_name = "SECONDS",
+        help = "Close a TCP relay this long after one side has finished sending"
+    )]
+    half_close_timeout: Option<u64>,
+    #[arg(
+        long = "handshake-timeout",
+        value_name = "SECONDS",
+        default_value_t = 10,
+        help = "Close connections that have not sent a request this long after connecting (0 to wait forever)"
+    )]
+    handshake_timeout: u64,
+    #[arg(
+        long = "limit",
+        value_name = "RATE[:BURST]",
+        help = "Limit the bandwidth of all clients together, in bytes per second with an optional K, M or G suffix, per direction"
+    )]
+    limit: Option<Rate>,
+    #[arg(
+        long = "limit-listener",
+        value_name = "RATE[:BURST]",
+        help = "Limit the bandwidth of the clients of each listener together, each tunnel counting as a listener"
+    )]
+    limit_listener: Option<Rate>,
+    #[arg(
+        long = "limit-user",
+        value_name = "RATE[:BURST]",
+        help = "Limit the bandwidth of each user, shared fairly by their sessions"
+    )]
+    limit_user: Option<Rate>,
+    #[arg(
+        long = "limit-client",
+        value_name = "RATE[:BURST]",
+        help = "Limit the bandwidth of each client IP address"
+    )]
+    limit_client: Option<Rate>,
+    #[arg(
+        long = "max-connections",
+        value_name = "N",
+        help = "Close connections beyond this many at once, including those still negotiating"
+    )]
+    max_connections: Option<usize>,
+    #[arg(
+        long = "max-sessions",
+        value_name = "N",
+        help = "Answer requests beyond this many concurrent sessions with a general failure"
+    )]
+    max_sessions: Option<usize>,
+    #[arg(
+        long = "max-sessions-per-client",
+        value_name = "N",
+        help = "Refuse requests beyond this many concurrent sessions from one client IP address"
+    )]
+    max_sessions_per_client: Option<usize>,
+    #[arg(
+        long = "max-sessions-per-user",
+        value_name = "N",
+        help = "Refuse requests beyond this many concurrent sessions of one user"
+    )]
+    max_sessions_per_user: Option<usize>,
+    #[arg(
+        long = "max-udp-per-client",
+        value_name = "N",
+        help = "Refuse UDP associations and UDP flows beyond this many from one client IP address"
+    )]
+    max_udp_per_client: Option<usize>,
+    #[arg(
+        long = "proxy-protocol",
+        value_name = "MODE",
+        default_value = "off",
+        help = "Accept a PROXY protocol v1/v2 header from clients: off, optional or required"
+    )]
+    proxy_protocol: ProxyMode,
+    #[arg(
+        long = "proxy-protocol-from",
+        value_name = "CIDR",
+        help = "Accept PROXY protocol headers only from this network, required with --proxy-protocol (may be repeated)"
+    )]
+    proxy_protocol_from: Vec<Cidr>,
+    #[arg(
+        long = "send-proxy-protocol",
+        value_name = "[v1=|v2=]DEST[:PORT]",
+        help = "Send a PROXY protocol header to targets in this CIDR or domain (may be repeated)"
+    )]
+    send_proxy_protocol: Vec<ProxyRule>,
+    #[arg(
+        long = "send-proxy-protocol-user",
+        requires = "send_proxy_protocol",
+        help = "Add the user of the session to v2 headers as a TLV of type 0xE0"
+    )]
+    send_proxy_protocol_user: bool,
+    #[arg(
+        long = "forward",
+        value_name = "[tcp/|udp/]LISTEN=HOST:PORT",
+        help = "Forward a local port to a fixed target, like ssh -L (may be repeated)"
+    )]
+    forward: Vec<Forward>,
+    #[cfg(target_os = "linux")]
+    #[arg(
+        long = "transparent",
+        value_name = "MODE",
+        help = "Act as a transparent proxy for connections steered here by redirect or tproxy rules"
+    )]
+    transparent: Option<TransparentMode>,
+    #[arg(
+        long = "log-level",
+        value_name = "LEVEL",
+        env = "SOCK5S_LOG",
+        default_value = "info",
+        help = "Log level: error, warn, info, debug or trace"
+    )]
+    log_level: Level,
+    #[arg(
+        long = "log-format",
+        value_name = "FORMAT",
+        env = "SOCK5S_LOG_FORMAT",
+        default_value = "text",
+        help = "Log format: text or json"
+    )]
+    log_format: log::Format,
+    #[arg(
+        long = "metrics",
+        value_name = "HOST:PORT",
+        help = "Serve Prometheus metrics over HTTP on this address"
+    )]
+    metrics: Option<SocketAddr>,
+    #[arg(
+        long = "admin",
+        value_name = "HOST:PORT",
+        help = "Serve the session admin API over HTTP on this loopback address"
+    )]
+    admin: Option<SocketAddr>,
+    #[cfg(feature = "tls")]
+    #[command(flatten)]
+    tls: TlsArgs,
+}
+
+#[cfg(feature = "tls")]
+#[derive(clap::Args, Debug)]
+struct TlsArgs {
+    #[arg(
+        long = "tls-cert",
+        value_name = "FILE",
+        requires = "tls_key",
+        help = "Accept SOCKS over TLS with this PEM certificate chain"
+    )]
+    tls_cert: Option<PathBuf>,
+    #[arg(
+        long = "tls-key",
+        value_name = "FILE",
+        requires = "tls_cert",
+        help = "PEM private key of --tls-cert"
+    )]
+    tls_key: Option<PathBuf>,
+    #[arg(
+        long = "tls-sni",
+        value_name = "NAME=CERT,KEY",
+        requires = "tls_cert",
+        value_parser = TlsOptions::parse_sni,
+        help = "Use another certificate for an SNI server name (may be repeated)"
+    )]
+    tls_sni: Vec<(String, PathBuf, PathBuf)>,
+    #[arg(
+        long = "tls-client-ca",
+        value_name = "FILE",
+        requires = "tls_cert",
+        help = "Verify client certificates against this PEM CA bundle"
+    )]
+    tls_client_ca: Option<PathBuf>,
+    #[arg(
+        long = "tls-require-client-cert",
+        requires = "tls_client_ca",
+        help = "Reject clients without a valid certificate"
+    )]
+    tls_require_client_cert: bool,
+    #[arg(
+        long = "tls-crl",
+        value_name = "FILE",
+        requires = "tls_client_ca",
+        help = "Reject client certificates revoked by this PEM CRL (may be repeated)"
+    )]
+    tls_crl: Vec<PathBuf>,
+    #[arg(
+        long = "tls-client-identity",
+        value_name = "FIELD",
+        default_value = "cn",
+        help = "Client certificate field used as the user: cn, san or fingerprint"
+    )]
+    tls_client_identity: CertIdentity,
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
+    match run().await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the server as configured on the command line.
+async fn run() -> Result<()> {
     let cli = Cli::parse();
-    let mut listener = Socks5Listener::listen(cli.listen).await?;
-    println!("Listening on: {}\n", cli.listen);
+    log::init(cli.log_level, cli.log_format);
+    let mut authenticators: Vec<Arc<dyn Authenticator>> = Vec::new();
+    if !cli.users.is_empty() {
+        authenticators.push(Arc::new(cli.users.into_iter().collect::<StaticUsers>()));
+    }
+    if let Some(path) = cli.users_file {
+        authenticators.push(Arc::new(FileUsers::open(path)?));
+    }
+    let limits = RateLimits {
+        global: cli.limit,
+        listener: cli.limit_listener,
+        user: cli.limit_user,
+        client: cli.limit_client,
+    };
+    let shaper = [limits.global, limits.listener, limits.user, limits.client]
+        .iter()
+        .any(Option::is_some)
+        .then(|| Arc::new(Shaper::new(limits)));
+    let limits = SessionLimits {
+        connections: cli.max_connections,
+        sessions: cli.max_sessions,
+        client: cli.max_sessions_per_client,
+        user: cli.max_sessions_per_user,
+        udp_client: cli.max_udp_per_client,
+    };
+    let admission = [
+        limits.connections,
+        limits.sessions,
+        limits.client,
+        limits.user,
+        limits.udp_client,
+    ]
+    .iter()
+    .any(Option::is_some)
+    .then(|| Arc::new(Admission::new(limits)));
+    let config = Config {
+        authenticators,
+        routes: cli.routes,
+        rules: cli.rules.into_iter().collect(),
+        half_close_timeout: cli.half_close_timeout.map(Duration::from_secs),
+        handshake_timeout: Some(cli.handshake_timeout)
+            .filter(|x| *x > 0)
+            .map(Duration::from_secs),
+        shaper,
+        admission,
+        proxy_protocol: cli.proxy_protocol,
+        proxy_protocol_from: cli.proxy_protocol_from,
+        send_proxy_protocol: cli.send_proxy_protocol,
+        send_proxy_protocol_user: cli.send_proxy_protocol_user,
+        forward: None,
+        #[cfg(target_os = "linux")]
+        transparent: cli.transparent,
+        #[cfg(feature = "tls")]
+        tls: match (cli.tls.tls_cert, cli.tls.tls_key) {
+            (Some(cert), Some(key)) => Some(
+                TlsOptions {
+                    cert,
+                    key,
+                    sni: cli.tls.tls_sni,
+                    client_ca: cli.tls.tls_client_ca,
+                    require_client_cert: cli.tls.tls_require_client_cert,
+                    crls: cli.tls.tls_crl,
+                }
+                .acceptor()?,
+            ),
+            _ => None,
+        },
+        #[cfg(feature = "tls")]
+        tls_identity: cli.tls.tls_client_identity,
+    };
+
+    // stdout and stderr are the client connection in inetd mode, and must
+    // not be written to any more.
+    #[cfg(target_family = "unix")]
+    if let ListenAddr::Inetd = cli.listen {
+        sock5s::detach_stdio()?;
+    }
+    let mut builder = Server::builder().config(config).listen(cli.listen);
+    #[cfg(target_family = "unix")]
+    {
+        let (uid, gid) = cli.unix_owner.unwrap_or_default();
+        // A spliced relay takes two sockets and two pipes.
+        // Every session is a connection too.
+        let fd_limit = match cli.max_connections.or(cli.max_sessions) {
+            Some(x) => (x as u64).saturating_mul(6).saturating_add(64).max(4096),
+            None => 4096,
+        };
+        builder = builder
+            .unix_options(UnixOptions {
+                mode: cli.unix_mode,
+                uid,
+                gid,
+            })
+            .fd_limit(fd_limit)
+            .systemd_notify();
+    }
+    for forward in cli.forward {
+        builder = builder.forward(forward);
+    }
+    if let Some(addr) = cli.metrics {
+        builder = builder.metrics(addr);
+    }
+    if let Some(addr) = cli.admin {
+        builder = builder.admin(addr);
+    }
+
+    let server = builder.build().await?;
+    let handle = server.handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        handle.shutdown();
+    });
+    server.run().await
+}
 
+/// Resolves on Ctrl-C, or SIGTERM on Unix.
+async fn shutdown_signal() {
     #[cfg(target_family = "unix")]
-    let _ = set_rlimit_nofile(4096);
+    {
+        use tokio::signal::unix::{SignalKind, signal};
 
-    while let Some((acceptor, client)) = listener.next().await.transpose()? {
-        tokio::spawn(async move {
-            match acceptor.accept().await {
-                Ok(_) => println!("{client} =! Closed."),
-                Err(e) => println!("{client} =! Error: {e}"),
+        if let Ok(mut term) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = term.recv() => {}
+                _ = tokio::signal::ctrl_c() => {}
             }
-        });
+            return;
+        }
     }
 
-    Ok(())
+    let _ = tokio::signal::ctrl_c().await;
 }
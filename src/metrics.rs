@@ -0,0 +1,301 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::*;
+
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reject {
+    /// Not a SOCKS5 greeting or a malformed request.
+    Protocol,
+    /// None of the offered authentication methods is acceptable.
+    AuthMethod,
+    /// The client failed authentication.
+    Auth,
+    Command,
+    AddressType,
+    ConnectFailed,
+    ServerFailure,
+    /// A session limit was reached.
+    Limit,
+    /// An access rule denied the target.
+    Rule,
+}
+
+pub struct Gauge(AtomicU64);
+
+pub struct GaugeGuard<'a>(&'a Gauge);
+
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+pub struct Metrics {
+    pub connections: AtomicU64,
+    pub tcp_sessions: Gauge,
+    pub udp_sessions: Gauge,
+    pub accepted: AtomicU64,
+    pub rejected: [AtomicU64; Reject::ALL.len()],
+    pub auth_failures: AtomicU64,
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub udp_packets_up: AtomicU64,
+    pub udp_packets_down: AtomicU64,
+    pub udp_drops: AtomicU64,
+    pub dns_cache_hits: AtomicU64,
+    pub dns_cache_misses: AtomicU64,
+    pub connect_latency: Histogram,
+}
+
+impl Reject {
+    const ALL: [Reject; 9] = [
+        Reject::Protocol,
+        Reject::AuthMethod,
+        Reject::Auth,
+        Reject::Command,
+        Reject::AddressType,
+        Reject::ConnectFailed,
+        Reject::ServerFailure,
+        Reject::Limit,
+        Reject::Rule,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Reject::Protocol => "protocol",
+            Reject::AuthMethod => "auth_method",
+            Reject::Auth => "auth",
+            Reject::Command => "command",
+            Reject::AddressType => "address_type",
+            Reject::ConnectFailed => "connect_failed",
+            Reject::ServerFailure => "server_failure",
+            Reject::Limit => "limit",
+            Reject::Rule => "rule",
+        }
+    }
+}
+
+impl Gauge {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn enter(&self) -> GaugeGuard<'_> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(self)
+    }
+
+    fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len()],
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, value: Duration) {
+        let secs = value.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros
+            .fetch_add(value.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            tcp_sessions: Gauge::new(),
+            udp_sessions: Gauge::new(),
+            accepted: AtomicU64::new(0),
+            rejected: [const { AtomicU64::new(0) }; Reject::ALL.len()],
+            auth_failures: AtomicU64::new(0),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            udp_packets_up: AtomicU64::new(0),
+            udp_packets_down: AtomicU64::new(0),
+            udp_drops: AtomicU64::new(0),
+            dns_cache_hits: AtomicU64::new(0),
+            dns_cache_misses: AtomicU64::new(0),
+            connect_latency: Histogram::new(),
+        }
+    }
+
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reject(&self, reason: Reject) {
+        Self::inc(&self.rejected[reason as usize]);
+        if matches!(reason, Reject::AuthMethod | Reject::Auth) {
+            Self::inc(&self.auth_failures);
+        }
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::with_capacity(4096);
+        let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, u64)]| {
+            let _ = writeln!(out, "# HELP sock5s_{name} {help}");
+            let _ = writeln!(out, "# TYPE sock5s_{name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "sock5s_{name}{labels} {value}");
+            }
+        };
+
+        metric(
+            "connections_total",
+            "counter",
+            "Client connections accepted by the listener.",
+            &[("", load(&self.connections))],
+        );
+        metric(
+            "active_sessions",
+            "gauge",
+            "Sessions currently relaying traffic.",
+            &[
+                ("{protocol=\"tcp\"}", self.tcp_sessions.get()),
+                ("{protocol=\"udp\"}", self.udp_sessions.get()),
+            ],
+        );
+        metric(
+            "requests_accepted_total",
+            "counter",
+            "SOCKS requests that passed the handshake.",
+            &[("", load(&self.accepted))],
+        );
+        let rejected: Vec<_> = Reject::ALL
+            .iter()
+            .map(|x| {
+                (
+                    format!("{{reason=\"{}\"}}", x.as_str()),
+                    load(&self.rejected[*x as usize]),
+                )
+            })
+            .collect();
+        let rejected: Vec<_> = rejected.iter().map(|(l, v)| (l.as_str(), *v)).collect();
+        metric(
+            "requests_rejected_total",
+            "counter",
+            "SOCKS requests rejected, by reason.",
+            &rejected,
+        );
+        metric(
+            "auth_failures_total",
+            "counter",
+            "Failed authentication attempts.",
+            &[("", load(&self.auth_failures))],
+        );
+        metric(
+            "bytes_total",
+            "counter",
+            "Payload bytes relayed, by direction.",
+            &[
+                ("{direction=\"up\"}", load(&self.bytes_up)),
+                ("{direction=\"down\"}", load(&self.bytes_down)),
+            ],
+        );
+        metric(
+            "udp_packets_total",
+            "counter",
+            "UDP datagrams relayed, by direction.",
+            &[
+                ("{direction=\"up\"}", load(&self.udp_packets_up)),
+                ("{direction=\"down\"}", load(&self.udp_packets_down)),
+            ],
+        );
+        metric(
+            "udp_drops_total",
+            "counter",
+            "UDP datagrams dropped as malformed, fragmented, denied, or for a target that was unresolvable or unreachable.",
+            &[("", load(&self.udp_drops))],
+        );
+        metric(
+            "dns_cache_total",
+            "counter",
+            "UDP relay DNS cache lookups, by result.",
+            &[
+                ("{result=\"hit\"}", load(&self.dns_cache_hits)),
+                ("{result=\"miss\"}", load(&self.dns_cache_misses)),
+            ],
+        );
+
+        let latency = &self.connect_latency;
+        let _ = writeln!(
+            out,
+            "# HELP sock5s_connect_duration_seconds Time taken to connect to the target."
+        );
+        let _ = writeln!(out, "# TYPE sock5s_connect_duration_seconds histogram");
+        for (bucket, bound) in latency.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "sock5s_connect_duration_seconds_bucket{{le=\"{bound}\"}} {}",
+                load(bucket)
+            );
+        }
+        let count = load(&latency.count);
+        let _ = writeln!(
+            out,
+            "sock5s_connect_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+        );
+        let _ = writeln!(
+            out,
+            "sock5s_connect_duration_seconds_sum {}",
+            load(&latency.sum_micros) as f64 / 1e6
+        );
+        let _ = writeln!(out, "sock5s_connect_duration_seconds_count {count}");
+
+        out
+    }
+}
+
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    http::serve(listener, move |request: http::Request| {
+        let response = handle(request, &metrics);
+        async move { response }
+    })
+    .await
+}
+
+fn handle(request: http::Request, metrics: &Metrics) -> http::Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/metrics") => http::Response::new(
+            200,
+            "text/plain; version=0.0.4; charset=utf-8",
+            metrics.render(),
+        ),
+        (_, "/metrics") => http::Response::text(405, "Method not allowed\n"),
+        _ => http::Response::text(404, "Not found\n"),
+    }
+}
@@ -0,0 +1,348 @@
+use std::str::FromStr;
+
+use super::*;
+
+/// The PROXY protocol v2 signature.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest possible PROXY protocol v1 header.
+const V1_MAX_LEN: usize = 107;
+/// The shortest possible PROXY protocol v1 header, `PROXY UNKNOWN\r\n`.
+const V1_MIN_LEN: usize = 15;
+/// The custom v2 TLV type carrying the authenticated user.
+const PP2_TYPE_USER: u8 = 0xe0;
+
+/// Whether clients are expected to send a PROXY protocol header.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProxyMode {
+    #[default]
+    Off,
+    /// Accept a header if there is one.
+    Optional,
+    /// Reject connections without a header.
+    Required,
+}
+
+/// Destinations that get a PROXY protocol header on outbound connections:
+/// `[v1=|v2=]PATTERN[:PORT]`, where the pattern is a CIDR matched against
+/// the connected address, or a domain (`*.` for subdomains) matched against
+/// the requested one.
+#[derive(Clone, Debug)]
+pub struct ProxyRule {
+    version: u8,
+    destination: TargetPattern,
+}
+
+impl FromStr for ProxyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "off" => ProxyMode::Off,
+            "optional" => ProxyMode::Optional,
+            "required" => ProxyMode::Required,
+            _ => return Err(format!("Invalid PROXY protocol mode: {s}!")),
+        })
+    }
+}
+
+impl FromStr for ProxyRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid PROXY protocol destination: {s}!");
+        let (version, dest) = match s.split_once('=') {
+            Some(("v1", x)) => (1, x),
+            Some(("v2", x)) => (2, x),
+            Some(_) => return Err(invalid()),
+            None => (2, s),
+        };
+        let destination = dest.parse().map_err(|_| invalid())?;
+        Ok(Self {
+            version,
+            destination,
+        })
+    }
+}
+
+/// Builds a PROXY protocol v1 header. Clients without an IP address are
+/// sent as `UNKNOWN`.
+fn v1_header(source: Option<SocketAddr>, destination: SocketAddr) -> Vec<u8> {
+    let Some(source) = source else {
+        return b"PROXY UNKNOWN\r\n".to_vec();
+    };
+    let (source, destination) = same_family(source, destination);
+    let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+    format!(
+        "PROXY {family} {} {} {} {}\r\n",
+        source.ip(),
+        destination.ip(),
+        source.port(),
+        destination.port()
+    )
+    .into_bytes()
+}
+
+/// Builds a PROXY protocol v2 header, with the user as a TLV if given.
+fn v2_header(source: Option<SocketAddr>, destination: SocketAddr, user: Option<&str>) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    let mut body = Vec::with_capacity(64);
+    let family = match source.map(|x| same_family(x, destination)) {
+        Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+            body.extend(source.ip().octets());
+            body.extend(destination.ip().octets());
+            body.extend(source.port().to_be_bytes());
+            body.extend(destination.port().to_be_bytes());
+            0x11
+        }
+        Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+            body.extend(source.ip().octets());
+            body.extend(destination.ip().octets());
+            body.extend(source.port().to_be_bytes());
+            body.extend(destination.port().to_be_bytes());
+            0x21
+        }
+        _ => 0x00,
+    };
+    // A user too long for the header is left out rather than cut short.
+    if let Some(user) = user
+        && u16::try_from(body.len() + 3 + user.len()).is_ok()
+    {
+        body.push(PP2_TYPE_USER);
+        body.extend((user.len() as u16).to_be_bytes());
+        body.extend(user.as_bytes());
+    }
+
+    header.extend([0x21, family]);
+    // At most 36 bytes of addresses, and the user only if it fits.
+    header.extend((body.len() as u16).to_be_bytes());
+    header.extend(body);
+    header
+}
+
+/// Both addresses of a header must be of the same family, so an IPv4
+/// address next to an IPv6 one is sent IPv4-mapped.
+fn same_family(a: SocketAddr, b: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let v6 = |x: SocketAddr| match x {
+        SocketAddr::V4(v4) => SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port()),
+        x => x,
+    };
+    if a.is_ipv4() == b.is_ipv4() {
+        (a, b)
+    } else {
+        (v6(a), v6(b))
+    }
+}
+
+/// The PROXY protocol header to send to `destination`, the connected
+/// address of `target`, if any rule asks for one.
+pub fn outbound_header(
+    config: &Config,
+    session: &Session,
+    target: &Socks5Target,
+    destination: SocketAddr,
+) -> Option<Vec<u8>> {
+    let rule = config
+        .send_proxy_protocol
+        .iter()
+        .find(|x| x.destination.matches(target, Some(destination.ip())))?;
+    let canonical = |x: SocketAddr| SocketAddr::new(x.ip().to_canonical(), x.port());
+    let destination = canonical(destination);
+    let source = match session.client() {
+        Socks5Peer::Inet(x) => Some(canonical(x)),
+        #[allow(unreachable_patterns)]
+        _ => None,
+    };
+
+    Some(match rule.version {
+        1 => v1_header(source, destination),
+        _ => v2_header(
+            source,
+            destination,
+            session
+                .user
+                .get()
+                .filter(|_| config.send_proxy_protocol_user)
+                .map(|x| x.user.as_str()),
+        ),
+    })
+}
+
+/// Parses a PROXY protocol v1 header line, including the trailing CRLF.
+/// Returns the source address, if the header conveys one.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>> {
+    let invalid = || Error::Protocol("Invalid PROXY protocol v1 header!".into());
+    let line = line
+        .strip_suffix(b"\r\n")
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .ok_or_else(invalid)?;
+
+    let mut parts = line.split(' ');
+    if parts.next() != Some("PROXY") {
+        return Err(invalid());
+    }
+    match parts.next() {
+        Some("UNKNOWN") => return Ok(None),
+        Some("TCP4" | "TCP6") => {}
+        _ => return Err(invalid()),
+    }
+
+    let mut next = || parts.next().ok_or_else(invalid);
+    let source: IpAddr = next()?.parse().map_err(|_| invalid())?;
+    let _destination: IpAddr = next()?.parse().map_err(|_| invalid())?;
+    let port: u16 = next()?.parse().map_err(|_| invalid())?;
+    let _port: u16 = next()?.parse().map_err(|_| invalid())?;
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(Some(SocketAddr::new(source, port)))
+}
+
+/// Parses the 16 byte fixed part of a PROXY protocol v2 header. Returns
+/// the command and address family bytes and the length of the rest.
+fn parse_v2_head(head: &[u8; 16]) -> Result<(u8, u8, usize)> {
+    if &head[..12] != V2_SIGNATURE || head[12] >> 4 != 2 {
+        return Err(Error::Protocol("Invalid PROXY protocol v2 header!".into()));
+    }
+    let len = u16::from_be_bytes([head[14], head[15]]) as usize;
+    Ok((head[12] & 0x0f, head[13], len))
+}
+
+/// Parses the addresses of a PROXY protocol v2 header. Returns the source
+/// address, if the header conveys one.
+fn parse_v2_addr(command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>> {
+    let invalid = || Error::Protocol("Invalid PROXY protocol v2 header!".into());
+    match command {
+        // LOCAL: health checks from the proxy itself.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid()),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if body.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&body[..4]).unwrap();
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if body.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&body[..16]).unwrap();
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_UNSPEC and AF_UNIX carry no usable address.
+        0 | 3 => Ok(None),
+        _ => Err(invalid()),
+    }
+}
+
+/// Reads a PROXY protocol header from the start of `stream`, if there is
+/// one. Returns whether a header was found, and the source address it
+/// conveys.
+async fn read_header(stream: &mut TcpStream) -> Result<(bool, Option<SocketAddr>)> {
+    // A SOCKS5 greeting starts with 0x05, while v1 headers start with 'P'
+    // and v2 headers with '\r', so a single byte tells them apart.
+    let mut first = [0];
+    if stream.peek(&mut first).await? == 0 {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+
+    match first[0] {
+        b'P' => {
+            let mut line = vec![0; V1_MIN_LEN];
+            stream.read_exact(&mut line).await?;
+            loop {
+                match line.windows(2).position(|x| x == b"\r\n") {
+                    Some(x) if x + 2 == line.len() => break,
+                    Some(_) => {
+                        return Err(Error::Protocol("Invalid PROXY protocol v1 header!".into()));
+                    }
+                    None => {}
+                }
+                if line.len() >= V1_MAX_LEN {
+                    return Err(Error::Protocol("PROXY protocol v1 header too long!".into()));
+                }
+                line.push(stream.read_u8().await?);
+            }
+            Ok((true, parse_v1(&line)?))
+        }
+        b'\r' => {
+            let mut head = [0; 16];
+            stream.read_exact(&mut head).await?;
+            let (command, family, len) = parse_v2_head(&head)?;
+            let mut body = vec![0; len];
+            stream.read_exact(&mut body).await?;
+            Ok((true, parse_v2_addr(command, family, &body)?))
+        }
+        _ => Ok((false, None)),
+    }
+}
+
+impl Socks5Acceptor {
+    /// Reads the PROXY protocol header of a connection from a trusted
+    /// proxy, and makes the client it conveys the client of the session.
+    pub async fn accept_proxy_header(&mut self) -> Result<()> {
+        let mode = self.config.proxy_protocol;
+        let Socks5Stream::Tcp(stream) = &mut self.stream else {
+            return Ok(());
+        };
+        if mode == ProxyMode::Off {
+            return Ok(());
+        }
+
+        let peer = stream.peer_addr()?.ip();
+        let trusted = &self.config.proxy_protocol_from;
+        if !trusted.iter().any(|x| x.contains(peer)) {
+            if mode == ProxyMode::Required {
+                self.session.metrics.reject(Reject::Protocol);
+                return Err(Error::Denied(format!(
+                    "Connection from untrusted proxy {peer}!"
+                )));
+            }
+            return Ok(());
+        }
+
+        let (found, source) = match read_header(stream).await {
+            Ok(x) => x,
+            Err(e) => {
+                self.session.metrics.reject(Reject::Protocol);
+                return Err(e);
+            }
+        };
+        if !found && mode == ProxyMode::Required {
+            self.session.metrics.reject(Reject::Protocol);
+            return Err(Error::Protocol("Missing PROXY protocol header!".into()));
+        }
+        if let Some(source) = source {
+            debug!(conn = self.session.id; "{peer} proxies {source}");
+            let _ = self.session.proxied.set(source);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn v2_header_length() {
+        let source = "192.0.2.1:1234".parse().ok();
+        let destination = "198.51.100.1:80".parse().unwrap();
+        let len = |x: &[u8]| u16::from_be_bytes([x[14], x[15]]) as usize;
+
+        let header = v2_header(source, destination, Some("alice"));
+        assert_eq!(len(&header), 12 + 3 + 5);
+        assert_eq!(header.len(), 16 + len(&header));
+
+        let fits = "x".repeat(u16::MAX as usize - 12 - 3);
+        let header = v2_header(source, destination, Some(&fits));
+        assert_eq!(len(&header), u16::MAX as usize);
+
+        let user = "x".repeat(fits.len() + 1);
+        let header = v2_header(source, destination, Some(&user));
+        assert_eq!(len(&header), 12);
+        assert_eq!(header.len(), 16 + 12);
+    }
+}
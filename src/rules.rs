@@ -0,0 +1,270 @@
+use std::str::FromStr;
+
+use super::*;
+
+/// What a [`Rule`] does with the targets it matches.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// An access rule, `allow=[USER@]DEST` or `deny=[USER@]DEST`, with USER
+/// and DEST as in routes. USER may also be `unix:USER[:GROUP]`, matching
+/// the credentials of Unix socket clients.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub action: Action,
+    /// Only sessions of this user are matched when set.
+    pub user: Option<Principal>,
+    pub targets: TargetPattern,
+}
+
+/// Whose sessions a [`Rule`] applies to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Principal {
+    /// An authenticated user.
+    User(String),
+    /// Unix socket clients running as this user or with this primary
+    /// group (SO_PEERCRED), both if given.
+    #[cfg(target_family = "unix")]
+    Unix { uid: Option<u32>, gid: Option<u32> },
+}
+
+/// Access rules checked in order, the first match deciding. Targets no rule
+/// matches are allowed.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet(Vec<Rule>);
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (action, targets) = match s.split_once('=') {
+            Some(("allow", x)) => (Action::Allow, x),
+            Some(("deny", x)) => (Action::Deny, x),
+            _ => return Err(format!("Invalid rule: {s}!")),
+        };
+        let (user, targets) = match targets.split_once('@') {
+            Some((user, targets)) => (Some(user.parse()?), targets),
+            None => (None, targets),
+        };
+        Ok(Self {
+            action,
+            user,
+            targets: targets.parse()?,
+        })
+    }
+}
+
+impl Rule {
+    /// Whether the rule applies to `target` for `session`.
+    pub fn matches(&self, target: &Socks5Target, addr: Option<IpAddr>, session: &Session) -> bool {
+        self.user.as_ref().is_none_or(|x| x.matches(session)) && self.targets.matches(target, addr)
+    }
+}
+
+impl Principal {
+    /// Whether `session` is of this user, or of this Unix socket client.
+    pub fn matches(&self, session: &Session) -> bool {
+        match (self, &session.peer) {
+            (Self::User(x), _) => session.user.get().is_some_and(|user| user.user == *x),
+            #[cfg(target_family = "unix")]
+            (Self::Unix { uid, gid }, Socks5Peer::Unix { uid: u, gid: g, .. }) => {
+                uid.is_none_or(|x| x == *u) && gid.is_none_or(|x| x == *g)
+            }
+            #[cfg(target_family = "unix")]
+            (Self::Unix { .. }, _) => false,
+        }
+    }
+}
+
+impl FromStr for Principal {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        #[cfg(target_family = "unix")]
+        if let Some(owner) = s.strip_prefix("unix:") {
+            let (uid, gid) = UnixOptions::parse_owner(owner)?;
+            return Ok(Self::Unix { uid, gid });
+        }
+        Ok(Self::User(s.to_owned()))
+    }
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Self(rules)
+    }
+
+    pub fn push(&mut self, rule: Rule) {
+        self.0.push(rule);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether `target` may be reached by `session`. CIDRs are matched
+    /// against `addr`, the address a domain resolved to, if known.
+    pub fn allows(&self, target: &Socks5Target, addr: Option<IpAddr>, session: &Session) -> bool {
+        self.0
+            .iter()
+            .find(|x| x.matches(target, addr, session))
+            .is_none_or(|x| x.action == Action::Allow)
+    }
+
+    /// Fails with [`Error::Denied`] unless `target` may be reached.
+    pub fn check(
+        &self,
+        target: &Socks5Target,
+        addr: Option<IpAddr>,
+        session: &Session,
+    ) -> Result<()> {
+        match self.allows(target, addr, session) {
+            true => Ok(()),
+            false => Err(denied(target)),
+        }
+    }
+
+    /// What to dial for `target` for `session`: with `resolve`,
+    /// the addresses a domain resolves to that may be reached, otherwise
+    /// `target` itself if it may be reached. Fails with [`Error::Denied`]
+    /// if nothing may be.
+    pub async fn resolve(
+        &self,
+        target: &Socks5Target,
+        resolve: bool,
+        session: &Session,
+    ) -> Result<Vec<Socks5Target>> {
+        let host = match &target.0 {
+            Socks5Host::Domain(x) if resolve => x.as_str(),
+            _ => {
+                self.check(target, None, session)?;
+                return Ok(vec![target.clone()]);
+            }
+        };
+
+        let addrs: Vec<_> = tokio::net::lookup_host((host, target.1))
+            .await
+            .map_err(Error::Connect)?
+            .collect();
+        if addrs.is_empty() {
+            let e = io::Error::new(ErrorKind::NotFound, format!("Could not resolve {host}!"));
+            return Err(Error::Connect(e));
+        }
+        let allowed: Vec<_> = addrs
+            .into_iter()
+            .filter(|x| self.allows(target, Some(x.ip()), session))
+            .map(Socks5Target::from)
+            .collect();
+        match allowed.is_empty() {
+            true => Err(denied(target)),
+            false => Ok(allowed),
+        }
+    }
+}
+
+fn denied(target: &Socks5Target) -> Error {
+    Error::Denied(format!("Target not allowed: {target}!"))
+}
+
+impl FromIterator<Rule> for RuleSet {
+    fn from_iter<T: IntoIterator<Item = Rule>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule_set(rules: &[&str]) -> RuleSet {
+        rules.iter().map(|x| x.parse().unwrap()).collect()
+    }
+
+    fn target(host: &str, port: u16) -> Socks5Target {
+        let host = match host.parse() {
+            Ok(ip) => Socks5Host::IpAddr(ip),
+            Err(_) => Socks5Host::Domain(host.into()),
+        };
+        Socks5Target(host, port)
+    }
+
+    fn session(user: Option<&str>) -> Session {
+        let session = Session::new(
+            Socks5Peer::Inet(([192, 0, 2, 1], 40000).into()),
+            Arc::default(),
+        );
+        if let Some(user) = user {
+            let _ = session.user.set(Identity { user: user.into() });
+        }
+        session
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let anyone = session(None);
+        let rules = rule_set(&["allow=10.1.0.0/16", "deny=10.0.0.0/8", "deny=*.example.com"]);
+        assert!(rules.allows(&target("10.1.2.3", 80), None, &anyone));
+        assert!(!rules.allows(&target("10.2.3.4", 80), None, &anyone));
+        assert!(rules.allows(&target("192.0.2.1", 80), None, &anyone));
+        assert!(!rules.allows(&target("www.example.com", 443), None, &anyone));
+        assert!(rules.allows(&target("example.org", 443), None, &anyone));
+
+        // Domains are checked against CIDRs by the address they resolve to.
+        let addr = Some("10.2.3.4".parse().unwrap());
+        assert!(rules.allows(&target("example.org", 443), None, &anyone));
+        assert!(!rules.allows(&target("example.org", 443), addr, &anyone));
+
+        let rules = rule_set(&["deny=10.0.0.0/8", "allow=10.1.0.0/16"]);
+        assert!(!rules.allows(&target("10.1.2.3", 80), None, &anyone));
+    }
+
+    #[test]
+    fn match_users() {
+        let rules = rule_set(&["allow=alice@10.0.0.0/8", "deny=10.0.0.0/8"]);
+        let ip = target("10.1.2.3", 80);
+        assert!(rules.allows(&ip, None, &session(Some("alice"))));
+        assert!(!rules.allows(&ip, None, &session(Some("bob"))));
+        assert!(!rules.allows(&ip, None, &session(None)));
+
+        #[cfg(target_family = "unix")]
+        {
+            let rules = rule_set(&["deny=unix:1000@*"]);
+            let unix = |uid| {
+                let peer = Socks5Peer::Unix {
+                    uid,
+                    gid: uid,
+                    pid: None,
+                };
+                Session::new(peer, Arc::default())
+            };
+            assert!(!rules.allows(&ip, None, &unix(1000)));
+            assert!(rules.allows(&ip, None, &unix(1001)));
+            assert!(rules.allows(&ip, None, &session(Some("1000"))));
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        assert!("allow=*".parse::<Rule>().is_ok());
+        assert!("deny=alice@10.0.0.0/8".parse::<Rule>().is_ok());
+        assert!("block=*".parse::<Rule>().is_err());
+        assert!("deny".parse::<Rule>().is_err());
+        assert!("deny=10.0.0.0/33".parse::<Rule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn resolve_into_denied_range() {
+        let anyone = session(None);
+        let rules = rule_set(&["deny=127.0.0.0/8", "deny=::1/128"]);
+        let localhost = target("localhost", 80);
+        let result = rules.resolve(&localhost, true, &anyone).await;
+        assert!(matches!(result, Err(Error::Denied(_))));
+        // Left to an upstream to resolve, the domain is not known to be in
+        // the range.
+        let result = rules.resolve(&localhost, false, &anyone).await;
+        assert_eq!(result.unwrap(), [localhost]);
+    }
+}
@@ -0,0 +1,402 @@
+use tokio::sync::watch;
+use tokio::task::JoinSet;
+
+use super::*;
+
+/// How long to pause accepting after an error, which is likely to recur
+/// at once when file descriptors run out.
+pub const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Sets up a [`Server`]: its listeners, tunnels, configuration and the
+/// optional metrics and admin endpoints.
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: Config,
+    listen: Vec<ListenAddr>,
+    tcp_listeners: Vec<TcpListener>,
+    forwards: Vec<Forward>,
+    metrics: Option<SocketAddr>,
+    admin: Option<SocketAddr>,
+    #[cfg(target_family = "unix")]
+    unix_options: UnixOptions,
+    #[cfg(target_family = "unix")]
+    fd_limit: Option<u64>,
+    #[cfg(target_family = "unix")]
+    systemd_notify: bool,
+}
+
+/// A SOCKS5 server, ready to [`run`](Server::run).
+pub struct Server {
+    listeners: Vec<Socks5Listener>,
+    udp_forwards: Vec<(UdpSocket, Socks5Target)>,
+    /// The TPROXY UDP sockets, one per TCP listener in TPROXY mode.
+    #[cfg(target_os = "linux")]
+    tproxy_udp: Vec<UdpSocket>,
+    /// The client connection of inetd mode, served instead of listening.
+    #[cfg(target_family = "unix")]
+    inetd: Option<Socks5Acceptor>,
+    #[cfg(target_family = "unix")]
+    fd_limit: Option<u64>,
+    #[cfg(target_family = "unix")]
+    systemd_notify: bool,
+    /// The configuration of the UDP tunnels and the TPROXY UDP relay,
+    /// which are served outside of the listeners.
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    /// The sessions in progress, for the admin API.
+    sessions: Arc<Registry>,
+    metrics_listener: Option<TcpListener>,
+    admin_listener: Option<TcpListener>,
+    shutdown: watch::Receiver<bool>,
+    handle: ServerHandle,
+}
+
+/// Stops a running [`Server`] from another task.
+#[derive(Clone)]
+pub struct ServerHandle(Arc<watch::Sender<bool>>);
+
+impl ServerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the configuration, including any authenticators added so
+    /// far.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a way for clients to authenticate. Without any, clients are
+    /// accepted without authentication.
+    pub fn authenticator(mut self, authenticator: impl Authenticator + 'static) -> Self {
+        self.config.authenticators.push(Arc::new(authenticator));
+        self
+    }
+
+    /// Dials targets matching the route through its dialers. Routes are
+    /// checked in the order they were added.
+    pub fn route(mut self, route: Route) -> Self {
+        self.config.routes.push(route);
+        self
+    }
+
+    /// Allows or denies targets. Rules are checked in the order they were
+    /// added, the first match deciding.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.config.rules.push(rule);
+        self
+    }
+
+    /// Limits the bandwidth of relays.
+    pub fn rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.shaper = Some(Arc::new(Shaper::new(limits)));
+        self
+    }
+
+    /// Limits the number of concurrent sessions.
+    pub fn session_limits(mut self, limits: SessionLimits) -> Self {
+        self.config.admission = Some(Arc::new(Admission::new(limits)));
+        self
+    }
+
+    /// Listens on `addr` for SOCKS clients. May be called more than once.
+    pub fn listen(mut self, addr: ListenAddr) -> Self {
+        self.listen.push(addr);
+        self
+    }
+
+    /// Serves SOCKS clients on a listener the caller has bound already.
+    pub fn tcp_listener(mut self, listener: TcpListener) -> Self {
+        self.tcp_listeners.push(listener);
+        self
+    }
+
+    /// Mode and ownership of the socket files of Unix listeners.
+    #[cfg(target_family = "unix")]
+    pub fn unix_options(mut self, options: UnixOptions) -> Self {
+        self.unix_options = options;
+        self
+    }
+
+    /// Adds a static tunnel to a fixed target.
+    pub fn forward(mut self, forward: Forward) -> Self {
+        self.forwards.push(forward);
+        self
+    }
+
+    /// Serves Prometheus metrics over HTTP on `addr`.
+    pub fn metrics(mut self, addr: SocketAddr) -> Self {
+        self.metrics = Some(addr);
+        self
+    }
+
+    /// Serves the session admin API over HTTP on the loopback address `addr`.
+    pub fn admin(mut self, addr: SocketAddr) -> Self {
+        self.admin = Some(addr);
+        self
+    }
+
+    /// Raises the soft limit on open files towards `limit` when run.
+    #[cfg(target_family = "unix")]
+    pub fn fd_limit(mut self, limit: u64) -> Self {
+        self.fd_limit = Some(limit);
+        self
+    }
+
+    /// Notifies systemd when ready and stopping, and feeds its watchdog.
+    #[cfg(target_family = "unix")]
+    pub fn systemd_notify(mut self) -> Self {
+        self.systemd_notify = true;
+        self
+    }
+
+    /// Checks the configuration and binds all listeners.
+    pub async fn build(self) -> Result<Server> {
+        #[cfg(all(target_os = "linux", feature = "tls"))]
+        if self.config.transparent.is_some() && self.config.tls.is_some() {
+            return Err("TLS is not supported in transparent mode!".into());
+        }
+        if self.config.proxy_protocol != ProxyMode::Off
+            && self.config.proxy_protocol_from.is_empty()
+        {
+            return Err("PROXY protocol headers need trusted sources!".into());
+        }
+        if let Some(addr) = self.admin
+            && !addr.ip().is_loopback()
+        {
+            return Err(format!("Admin address must be a loopback address: {addr}!").into());
+        }
+
+        let (sender, shutdown) = watch::channel(false);
+        let config = Arc::new(self.config);
+        let metrics = Arc::new(Metrics::default());
+        let mut server = Server {
+            listeners: Vec::new(),
+            udp_forwards: Vec::new(),
+            #[cfg(target_os = "linux")]
+            tproxy_udp: Vec::new(),
+            #[cfg(target_family = "unix")]
+            inetd: None,
+            #[cfg(target_family = "unix")]
+            fd_limit: self.fd_limit,
+            #[cfg(target_family = "unix")]
+            systemd_notify: self.systemd_notify,
+            config: config.clone(),
+            metrics: metrics.clone(),
+            sessions: Arc::default(),
+            metrics_listener: None,
+            admin_listener: None,
+            shutdown,
+            handle: ServerHandle(Arc::new(sender)),
+        };
+
+        for addr in &self.listen {
+            match addr {
+                ListenAddr::Tcp(addr) => {
+                    let listener =
+                        Socks5Listener::listen(addr, config.clone(), metrics.clone()).await?;
+                    #[cfg(target_os = "linux")]
+                    if config.transparent == Some(TransparentMode::Tproxy) {
+                        server.tproxy_udp.push(transparent::bind_udp(*addr)?);
+                    }
+                    server.listeners.push(listener);
+                }
+                #[cfg(target_family = "unix")]
+                ListenAddr::Unix(path) => server.listeners.push(Socks5Listener::listen_unix(
+                    path,
+                    &self.unix_options,
+                    config.clone(),
+                    metrics.clone(),
+                )?),
+                #[cfg(target_family = "unix")]
+                ListenAddr::Systemd => {
+                    for fd in activation::listen_fds()? {
+                        server.listeners.push(Socks5Listener::from_fd(
+                            fd,
+                            config.clone(),
+                            metrics.clone(),
+                        )?);
+                    }
+                }
+                #[cfg(target_family = "unix")]
+                ListenAddr::Inetd => {
+                    // stdin is the client connection, and so are stdout and
+                    // stderr, which the program must have detached.
+                    let (stream, client) = activation::inherited_stream(0)?;
+                    Metrics::inc(&metrics.connections);
+                    let acceptor =
+                        Socks5Acceptor::new(stream, client, config.clone(), metrics.clone());
+                    server.inetd = Some(acceptor);
+                    continue;
+                }
+            }
+            info!("Listening on: {addr}");
+        }
+        for listener in self.tcp_listeners {
+            server.listeners.push(Socks5Listener::from_listener(
+                listener,
+                config.clone(),
+                metrics.clone(),
+            )?);
+        }
+
+        for forward in self.forwards {
+            info!("Forwarding: {forward}");
+            if forward.udp {
+                let socket = UdpSocket::bind(forward.listen).await?;
+                server.udp_forwards.push((socket, forward.target));
+                continue;
+            }
+            // Tunnels take plain connections, whatever the main listeners do,
+            // and have bandwidth buckets of their own like any listener.
+            let config = Config {
+                forward: Some(forward.target),
+                proxy_protocol: ProxyMode::Off,
+                #[cfg(target_os = "linux")]
+                transparent: None,
+                #[cfg(feature = "tls")]
+                tls: None,
+                ..(*config).clone()
+            };
+            let listener =
+                Socks5Listener::listen(forward.listen, Arc::new(config), metrics.clone()).await?;
+            server.listeners.push(listener);
+        }
+        if let Some(addr) = self.metrics {
+            server.metrics_listener = Some(TcpListener::bind(addr).await?);
+            info!("Metrics listening on: {addr}");
+        }
+        if let Some(addr) = self.admin {
+            server.admin_listener = Some(TcpListener::bind(addr).await?);
+            info!("Admin API listening on: {addr}");
+        }
+
+        Ok(server)
+    }
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+
+    pub fn handle(&self) -> ServerHandle {
+        self.handle.clone()
+    }
+
+    /// The addresses of the TCP listeners, e.g. to find out the port when
+    /// binding port 0.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|x| x.local_addr().ok())
+            .collect()
+    }
+
+    /// Accepts and serves clients until shut down through the handle. The
+    /// metrics and admin endpoints and the UDP relays stop with it.
+    pub async fn run(mut self) -> Result<()> {
+        #[cfg(target_family = "unix")]
+        if let Some(acceptor) = self.inetd.take() {
+            serve(acceptor, &self.sessions).await;
+            return Ok(());
+        }
+
+        #[cfg(target_family = "unix")]
+        if let Some(limit) = self.fd_limit {
+            let _ = set_rlimit_nofile(limit);
+        }
+
+        let mut tasks = JoinSet::new();
+        if let Some(listener) = self.admin_listener.take() {
+            let sessions = self.sessions.clone();
+            tasks.spawn(async move {
+                if let Err(e) = admin::serve(listener, sessions).await {
+                    log!(Level::Error, "Admin server failed: {e}");
+                }
+            });
+        }
+        if let Some(listener) = self.metrics_listener.take() {
+            tasks.spawn(metrics::serve(listener, self.metrics.clone()));
+        }
+
+        #[cfg(target_os = "linux")]
+        for socket in self.tproxy_udp.drain(..) {
+            let (config, metrics) = (self.config.clone(), self.metrics.clone());
+            let sessions = self.sessions.clone();
+            tasks.spawn(async move {
+                if let Err(e) = transparent::serve_udp(socket, config, metrics, sessions).await {
+                    log!(Level::Error, "Transparent UDP relay failed: {e}");
+                }
+            });
+        }
+        for (socket, target) in self.udp_forwards.drain(..) {
+            let (config, metrics) = (self.config.clone(), self.metrics.clone());
+            let sessions = self.sessions.clone();
+            tasks.spawn(async move {
+                if let Err(e) = forward::serve_udp(socket, target, config, metrics, sessions).await
+                {
+                    log!(Level::Error, "UDP forward failed: {e}");
+                }
+            });
+        }
+
+        #[cfg(target_family = "unix")]
+        if self.systemd_notify {
+            activation::notify("READY=1");
+            tasks.spawn(activation::watchdog());
+        }
+
+        let mut listeners: StreamMap<_, _> = self.listeners.into_iter().enumerate().collect();
+        let mut shutdown = self.shutdown;
+        loop {
+            let accepted = tokio::select! {
+                x = listeners.next() => x,
+                _ = shutdown.wait_for(|x| *x) => {
+                    info!("Shutting down.");
+                    break;
+                }
+            };
+            match accepted {
+                Some((_, Ok((acceptor, _)))) => {
+                    let sessions = self.sessions.clone();
+                    tokio::spawn(async move { serve(acceptor, &sessions).await });
+                }
+                // Out of file descriptors or memory, or a connection reset
+                // before it was accepted: none of it is fatal.
+                Some((_, Err(e))) => {
+                    log!(Level::Error, "Failed to accept a connection: {e}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                }
+                None => break,
+            }
+        }
+
+        #[cfg(target_family = "unix")]
+        if self.systemd_notify {
+            activation::notify("STOPPING=1");
+        }
+        tasks.shutdown().await;
+        Ok(())
+    }
+}
+
+impl ServerHandle {
+    /// Makes the server stop accepting clients. Sessions in progress are
+    /// not interrupted.
+    pub fn shutdown(&self) {
+        self.0.send_replace(true);
+    }
+}
+
+/// Serves one client until it is done or killed through the admin API.
+async fn serve(acceptor: Socks5Acceptor, sessions: &Arc<Registry>) {
+    let session = acceptor.session.clone();
+    let _registered = sessions.register(&session);
+    let result = tokio::select! {
+        r = acceptor.accept() => r,
+        _ = session.killed() => Ok("killed by admin"),
+    };
+    session.log_close(&result);
+}
@@ -0,0 +1,191 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use tokio::sync::Notify;
+
+use super::*;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The limiter of sessions without bandwidth limits.
+static UNLIMITED: Limiter = Limiter::NONE;
+
+/// The sessions of a server that are currently being served.
+#[derive(Default)]
+pub struct Registry(Mutex<HashMap<u64, Arc<Session>>>);
+
+pub struct Registered(Arc<Registry>, u64);
+
+/// The traffic of a session, also counted in the metrics of its server.
+pub struct Traffic {
+    pub bytes_up: AtomicU64,
+    pub bytes_down: AtomicU64,
+    pub packets_up: AtomicU64,
+    pub packets_down: AtomicU64,
+    metrics: Arc<Metrics>,
+}
+
+pub struct Session {
+    pub id: u64,
+    /// The connected peer, which may be a proxy in front of the client.
+    pub peer: Socks5Peer,
+    /// The client conveyed by a PROXY protocol header.
+    pub proxied: OnceLock<SocketAddr>,
+    pub started: Instant,
+    pub user: OnceLock<Identity>,
+    pub command: OnceLock<&'static str>,
+    pub target: OnceLock<Socks5Target>,
+    pub traffic: Traffic,
+    /// The limiters for uploads and downloads, once relaying starts.
+    pub limiters: OnceLock<(Limiter, Limiter)>,
+    /// The metrics of the server serving the session.
+    pub metrics: Arc<Metrics>,
+    kill: Notify,
+}
+
+impl Traffic {
+    fn new(metrics: Arc<Metrics>) -> Self {
+        Self {
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            packets_up: AtomicU64::new(0),
+            packets_down: AtomicU64::new(0),
+            metrics,
+        }
+    }
+
+    pub fn add_up(&self, bytes: usize) {
+        self.bytes_up.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.metrics
+            .bytes_up
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_down(&self, bytes: usize) {
+        self.bytes_down.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.metrics
+            .bytes_down
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn packet_up(&self, bytes: usize) {
+        self.add_up(bytes);
+        self.packets_up.fetch_add(1, Ordering::Relaxed);
+        Metrics::inc(&self.metrics.udp_packets_up);
+    }
+
+    pub fn packet_down(&self, bytes: usize) {
+        self.add_down(bytes);
+        self.packets_down.fetch_add(1, Ordering::Relaxed);
+        Metrics::inc(&self.metrics.udp_packets_down);
+    }
+}
+
+impl Session {
+    /// A new session of the server with `metrics`.
+    pub fn new(peer: Socks5Peer, metrics: Arc<Metrics>) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            proxied: OnceLock::new(),
+            started: Instant::now(),
+            user: OnceLock::new(),
+            command: OnceLock::new(),
+            target: OnceLock::new(),
+            traffic: Traffic::new(metrics.clone()),
+            limiters: OnceLock::new(),
+            metrics,
+            kill: Notify::new(),
+        }
+    }
+
+    /// Asks the task serving this session to drop it.
+    pub fn kill(&self) {
+        self.kill.notify_one();
+    }
+
+    /// The client of the session, as conveyed by a trusted proxy if any.
+    pub fn client(&self) -> Socks5Peer {
+        self.proxied
+            .get()
+            .map_or(self.peer, |x| Socks5Peer::Inet(*x))
+    }
+
+    /// The limiter for uploads, which does not limit anything if unset.
+    pub fn limit_up(&self) -> &Limiter {
+        self.limiters.get().map_or(&UNLIMITED, |x| &x.0)
+    }
+
+    /// The limiter for downloads, which does not limit anything if unset.
+    pub fn limit_down(&self) -> &Limiter {
+        self.limiters.get().map_or(&UNLIMITED, |x| &x.1)
+    }
+
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    /// Logs the close record of this session, `result` being the outcome of
+    /// `Socks5Acceptor::accept`.
+    pub fn log_close(&self, result: &Result<&'static str>) {
+        let (level, status) = match result {
+            Ok(reason) => (Level::Info, format!("Closed ({reason}).")),
+            Err(e) => (Level::Warn, format!("Error: {e}")),
+        };
+        let udp = self.command.get() == Some(&"UDP");
+        let packets = |x: &AtomicU64| udp.then(|| x.load(Ordering::Relaxed));
+
+        log!(
+            level,
+            conn = self.id,
+            event = "close",
+            client = self.client(),
+            user = self.user.get(),
+            command = self.command.get().copied(),
+            target = self.target.get(),
+            bytes_up = self.traffic.bytes_up.load(Ordering::Relaxed),
+            bytes_down = self.traffic.bytes_down.load(Ordering::Relaxed),
+            packets_up = packets(&self.traffic.packets_up),
+            packets_down = packets(&self.traffic.packets_down),
+            duration_ms = self.started.elapsed().as_millis() as u64,
+            reason = result.as_ref().map_or_else(|e| e.to_string(), |x| x.to_string()),
+            error = result.as_ref().err().map(Error::category);
+            "{} =! {status}",
+            self.client()
+        );
+    }
+}
+
+impl Registry {
+    /// Adds `session` to the registry until the returned guard is dropped.
+    pub fn register(self: &Arc<Self>, session: &Arc<Session>) -> Registered {
+        let mut sessions = self.0.lock().unwrap();
+        sessions.insert(session.id, session.clone());
+        Registered(self.clone(), session.id)
+    }
+
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        let sessions = self.0.lock().unwrap();
+        let mut list: Vec<_> = sessions.values().cloned().collect();
+        list.sort_by_key(|x| x.id);
+        list
+    }
+
+    /// Kills every session matching `filter` and returns how many there were.
+    pub fn kill(&self, filter: impl Fn(&Session) -> bool) -> usize {
+        let sessions = self.list();
+        let mut killed = 0;
+        for x in sessions.iter().filter(|x| filter(x)) {
+            x.kill();
+            killed += 1;
+        }
+        killed
+    }
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        self.0.0.lock().unwrap().remove(&self.1);
+    }
+}
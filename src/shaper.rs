@@ -0,0 +1,259 @@
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use super::*;
+
+/// The most relayed at once while shaping, so that sessions drawing from
+/// the same bucket take turns in small steps.
+const QUANTUM: usize = 16384;
+
+/// A bandwidth limit: `RATE[:BURST]` in bytes per second, with an optional
+/// `K`, `M` or `G` suffix (powers of 1024). The burst defaults to one
+/// second at the rate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rate {
+    pub rate: u64,
+    pub burst: u64,
+}
+
+/// Bandwidth limits, each applying to uploads and downloads separately.
+#[derive(Clone, Debug, Default)]
+pub struct RateLimits {
+    /// Shared by all clients.
+    pub global: Option<Rate>,
+    /// Shared by the clients of each listener. Every tunnel, and the UDP
+    /// socket of transparent mode, counts as a listener of its own.
+    pub listener: Option<Rate>,
+    /// Shared by the sessions of each user.
+    pub user: Option<Rate>,
+    /// Shared by the sessions from each client IP address.
+    pub client: Option<Rate>,
+}
+
+/// Enforces [`RateLimits`] with token buckets.
+pub struct Shaper {
+    limits: RateLimits,
+    global: Option<Buckets>,
+    users: Mutex<HashMap<String, Buckets>>,
+    clients: Mutex<HashMap<IpAddr, Buckets>>,
+}
+
+/// The buckets of one limit, one for each direction.
+#[derive(Clone)]
+pub struct Buckets {
+    up: Arc<TokenBucket>,
+    down: Arc<TokenBucket>,
+}
+
+/// The buckets a session draws from in one direction.
+#[derive(Clone, Default)]
+pub struct Limiter(Vec<Arc<TokenBucket>>);
+
+struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    /// The tokens left, negative while in debt, as of the instant.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl FromStr for Rate {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate: {s}!");
+        let bytes = |x: &str| -> Option<u64> {
+            let (number, shift) = match x.as_bytes().last()? {
+                b'K' | b'k' => (&x[..x.len() - 1], 10),
+                b'M' | b'm' => (&x[..x.len() - 1], 20),
+                b'G' | b'g' => (&x[..x.len() - 1], 30),
+                _ => (x, 0),
+            };
+            let x: u64 = number.parse().ok()?;
+            x.checked_mul(1 << shift).filter(|x| *x > 0)
+        };
+
+        let (rate, burst) = match s.split_once(':') {
+            Some((rate, burst)) => (rate, Some(burst)),
+            None => (s, None),
+        };
+        let rate = bytes(rate).ok_or_else(invalid)?;
+        let burst = match burst {
+            Some(x) => bytes(x).ok_or_else(invalid)?,
+            None => rate,
+        };
+        Ok(Self { rate, burst })
+    }
+}
+
+impl Shaper {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            global: limits.global.map(Buckets::new),
+            limits,
+            users: Mutex::default(),
+            clients: Mutex::default(),
+        }
+    }
+
+    /// New buckets for a listener, if listeners are limited.
+    pub fn listener(&self) -> Option<Buckets> {
+        self.limits.listener.map(Buckets::new)
+    }
+
+    /// The limiters of a session, for uploads and downloads, drawing from
+    /// the buckets of every limit that applies to it.
+    pub fn session(&self, listener: Option<&Buckets>, session: &Session) -> (Limiter, Limiter) {
+        let mut buckets: Vec<_> = self.global.iter().chain(listener).cloned().collect();
+        if let (Some(rate), Some(user)) = (self.limits.user, session.user.get()) {
+            buckets.push(shared(&self.users, &user.user, rate));
+        }
+        if let (Some(rate), Socks5Peer::Inet(client)) = (self.limits.client, session.client()) {
+            buckets.push(shared(&self.clients, &client.ip(), rate));
+        }
+
+        let up = buckets.iter().map(|x| x.up.clone()).collect();
+        let down = buckets.into_iter().map(|x| x.down).collect();
+        (Limiter(up), Limiter(down))
+    }
+}
+
+/// The buckets of `key`, created if no session holds them.
+fn shared<K>(map: &Mutex<HashMap<K, Buckets>>, key: &K, rate: Rate) -> Buckets
+where
+    K: Clone + Eq + std::hash::Hash,
+{
+    let mut map = map.lock().unwrap();
+    if let Some(x) = map.get(key) {
+        return x.clone();
+    }
+    // Forget the buckets nobody uses any more.
+    map.retain(|_, x| Arc::strong_count(&x.up) > 1);
+    map.entry(key.clone()).or_insert(Buckets::new(rate)).clone()
+}
+
+impl Buckets {
+    fn new(rate: Rate) -> Self {
+        Self {
+            up: Arc::new(TokenBucket::new(rate)),
+            down: Arc::new(TokenBucket::new(rate)),
+        }
+    }
+}
+
+impl Limiter {
+    pub const NONE: Limiter = Limiter(Vec::new());
+
+    /// How much to relay at once, at most `max`.
+    pub fn chunk(&self, max: usize) -> usize {
+        match self.0.is_empty() {
+            true => max,
+            false => max.min(QUANTUM),
+        }
+    }
+
+    /// Takes `bytes` from every bucket unless one is in debt, for datagrams
+    /// that are dropped rather than delayed.
+    pub fn try_consume(&self, bytes: usize) -> bool {
+        if self.0.iter().any(|x| !x.take(0).is_zero()) {
+            return false;
+        }
+        for x in &self.0 {
+            x.take(bytes);
+        }
+        true
+    }
+
+    /// Takes `bytes` from every bucket, waiting until none is in debt.
+    pub async fn consume(&self, bytes: usize) {
+        let delay = self.0.iter().map(|x| x.take(bytes)).max();
+        if let Some(x) = delay.filter(|x| !x.is_zero()) {
+            tokio::time::sleep(x).await;
+        }
+    }
+}
+
+impl TokenBucket {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate: rate.rate as f64,
+            burst: rate.burst as f64,
+            state: Mutex::new((rate.burst as f64, Instant::now())),
+        }
+    }
+
+    /// Takes `bytes` and returns how long until the bucket is out of debt.
+    /// Later takers wait behind earlier ones, so sessions sharing the
+    /// bucket get their turns in order.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.1).as_secs_f64() * self.rate;
+        let tokens = (state.0 + refill).min(self.burst) - bytes as f64;
+        *state = (tokens, now);
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(rate: u64, burst: u64) -> Arc<TokenBucket> {
+        Arc::new(TokenBucket::new(Rate { rate, burst }))
+    }
+
+    /// Moves the last take of `bucket` back by `seconds`.
+    fn rewind(bucket: &TokenBucket, seconds: u64) {
+        bucket.state.lock().unwrap().1 -= Duration::from_secs(seconds);
+    }
+
+    #[test]
+    fn parse_rates() {
+        let rate = |s: &str| s.parse::<Rate>().ok().map(|x| (x.rate, x.burst));
+        assert_eq!(rate("1000"), Some((1000, 1000)));
+        assert_eq!(rate("1M:64K"), Some((1 << 20, 64 << 10)));
+        assert_eq!(rate("2g"), Some((2 << 30, 2 << 30)));
+        assert_eq!(rate("0"), None);
+        assert_eq!(rate("1000:0"), None);
+        assert_eq!(rate("1T"), None);
+        assert_eq!(rate(""), None);
+    }
+
+    #[test]
+    fn burst_then_rate() {
+        let bucket = bucket(1000, 500);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        let delay = bucket.take(500);
+        assert!(delay > Duration::from_millis(490) && delay <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refill_up_to_burst() {
+        let bucket = bucket(1000, 500);
+        bucket.take(1600);
+        rewind(&bucket, 1);
+        assert!(!bucket.take(0).is_zero());
+        rewind(&bucket, 1);
+        assert_eq!(bucket.take(0), Duration::ZERO);
+
+        // Idling for long never saves up more than the burst.
+        rewind(&bucket, 60);
+        assert_eq!(bucket.take(500), Duration::ZERO);
+        assert!(!bucket.take(1).is_zero());
+    }
+
+    #[test]
+    fn drop_datagrams_in_debt() {
+        let a = bucket(1000, 1000);
+        let limiter = Limiter(vec![a.clone(), bucket(1000, 100)]);
+        assert!(limiter.try_consume(200));
+        assert!(!limiter.try_consume(100));
+        // Nothing is taken from either bucket for a dropped datagram.
+        assert_eq!(a.take(800), Duration::ZERO);
+        assert!(!a.take(50).is_zero());
+    }
+}
@@ -0,0 +1,83 @@
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tokio::io::Interest;
+
+use super::*;
+
+const PIPE_SIZE: usize = 1 << 16;
+
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            })
+        }
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let ret = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret as usize)
+}
+
+/// Moves bytes from `reader` to `writer` through `pipe` without copying them
+/// into userspace, and shuts down the write half of `writer` on EOF. The
+/// size of every chunk moved is passed to `count`, then drawn from `limiter`.
+pub async fn splice_one(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    pipe: &Pipe,
+    count: impl Fn(usize),
+    limiter: &Limiter,
+) -> io::Result<()> {
+    let max = limiter.chunk(PIPE_SIZE);
+    loop {
+        // The pipe is always drained before the next read, so EAGAIN here
+        // means the socket has no data rather than the pipe being full.
+        let len = reader
+            .async_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.write.as_raw_fd(), max)
+            })
+            .await?;
+        if len == 0 {
+            break;
+        }
+
+        let mut left = len;
+        while left > 0 {
+            left -= writer
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), left)
+                })
+                .await?;
+        }
+        count(len);
+        limiter.consume(len).await;
+    }
+
+    shutdown_write(writer)?;
+    Ok(())
+}
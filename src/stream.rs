@@ -0,0 +1,135 @@
+use tokio::io::ReadBuf;
+#[cfg(target_family = "unix")]
+use tokio::net::UnixStream;
+#[cfg(feature = "tls")]
+use tokio_rustls::server::TlsStream;
+
+use super::*;
+
+/// A client connection accepted by `Socks5Listener`.
+pub enum Socks5Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(target_family = "unix")]
+    Unix(UnixStream),
+}
+
+/// Where a client connection comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Socks5Peer {
+    Inet(SocketAddr),
+    /// A Unix socket client, identified by its credentials (SO_PEERCRED).
+    #[cfg(target_family = "unix")]
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+}
+
+impl Socks5Stream {
+    /// The underlying TCP connection, if any.
+    pub fn tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(x) => Some(x),
+            #[cfg(feature = "tls")]
+            Self::Tls(x) => Some(x.get_ref().0),
+            #[cfg(target_family = "unix")]
+            Self::Unix(_) => None,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().ok_or_else(not_inet)?.local_addr()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp().ok_or_else(not_inet)?.peer_addr()
+    }
+}
+
+fn not_inet() -> io::Error {
+    io::Error::new(ErrorKind::Unsupported, "Not an IP connection!")
+}
+
+impl From<TcpStream> for Socks5Stream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+#[cfg(target_family = "unix")]
+impl From<UnixStream> for Socks5Stream {
+    fn from(stream: UnixStream) -> Self {
+        Self::Unix(stream)
+    }
+}
+
+impl Display for Socks5Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(x) => x.fmt(f),
+            #[cfg(target_family = "unix")]
+            Self::Unix { uid, gid, pid } => {
+                write!(f, "unix:uid={uid},gid={gid}")?;
+                match pid {
+                    Some(x) => write!(f, ",pid={x}"),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for Socks5Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(x) => Pin::new(x).poll_read(cx, buf),
+            #[cfg(target_family = "unix")]
+            Self::Unix(x) => Pin::new(x).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Socks5Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Self::Tls(x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(target_family = "unix")]
+            Self::Unix(x) => Pin::new(x).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(x) => Pin::new(x).poll_flush(cx),
+            #[cfg(target_family = "unix")]
+            Self::Unix(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Self::Tls(x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(target_family = "unix")]
+            Self::Unix(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
@@ -1,14 +1,35 @@
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use idna::uts46::{AsciiDenyList, DnsLength, Hyphens, Uts46};
+
 use super::*;
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Socks5Host {
     IpAddr(IpAddr),
     Domain(String),
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Socks5Target(pub Socks5Host, pub u16);
 
+/// Matches targets: `PATTERN[:PORT]`, where the pattern is a CIDR matched
+/// against the target address, a domain (`*.` for subdomains) matched
+/// against the requested one, or `*` for any target.
+#[derive(Clone, Debug)]
+pub struct TargetPattern {
+    pattern: Pattern,
+    port: Option<u16>,
+}
+
+#[derive(Clone, Debug)]
+enum Pattern {
+    Any,
+    Cidr(Cidr),
+    Domain(String),
+}
+
 impl Display for Socks5Host {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -28,52 +49,265 @@ impl Display for Socks5Target {
     }
 }
 
-impl Socks5Target {
-    fn parse_ipv4(data: &[u8]) -> Self {
-        debug_assert_eq!(data.len(), 6);
-        let ip = Ipv4Addr::from_octets(data[0..4].try_into().unwrap());
-        let port = u16::from_be_bytes([data[4], data[5]]);
-        Self(Socks5Host::IpAddr(IpAddr::V4(ip)), port)
+impl Socks5Host {
+    /// Converts a domain to lowercase ASCII (IDNA, UTS #46), checking its
+    /// labels, so that it is matched and resolved as one name however it
+    /// was spelled. Domains spelling an address, in any form the resolver
+    /// would take, become that address. Addresses are left as they are.
+    pub fn normalize(self) -> Result<Self> {
+        match &self {
+            Socks5Host::IpAddr(_) => Ok(self),
+            Socks5Host::Domain(x) => {
+                normalize_host(x).ok_or_else(|| Error::InvalidDomain(x.clone()))
+            }
+        }
+    }
+}
+
+/// The address `host` spells, or its normalized domain. Names ending in a
+/// number that is not part of an IPv4 address are invalid, as in URLs.
+fn normalize_host(host: &str) -> Option<Socks5Host> {
+    let literal = host.strip_prefix('[').and_then(|x| x.strip_suffix(']'));
+    if let Ok(x) = literal.unwrap_or(host).parse::<Ipv6Addr>() {
+        return Some(Socks5Host::IpAddr(x.to_canonical()));
+    }
+
+    let domain = normalize_domain(host)?;
+    let name = domain.strip_suffix('.').unwrap_or(&domain);
+    match parse_ipv4(name) {
+        Some(x) => Some(Socks5Host::IpAddr(x.into())),
+        None if ends_in_number(name) => None,
+        None => Some(Socks5Host::Domain(domain)),
+    }
+}
+
+/// Parses a lowercase IPv4 address as `inet_aton` does: one to four
+/// numbers, each decimal, octal with a leading `0` or hexadecimal with
+/// `0x`, the last filling the remaining bytes.
+fn parse_ipv4(s: &str) -> Option<Ipv4Addr> {
+    let parts: Vec<_> = s.split('.').map(parse_number).collect::<Option<_>>()?;
+    let (last, init) = parts.split_last()?;
+    if init.len() > 3 || init.iter().any(|&x| x > 0xff) {
+        return None;
+    }
+    if u64::from(*last) >> (32 - 8 * init.len()) != 0 {
+        return None;
+    }
+    let init = init.iter().enumerate().map(|(i, &x)| x << (24 - 8 * i));
+    Some(Ipv4Addr::from_bits(init.fold(*last, |x, y| x | y)))
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    let (digits, radix) = match s.strip_prefix("0x") {
+        Some(x) => (x, 16),
+        None if s.len() > 1 && s.starts_with('0') => (&s[1..], 8),
+        None => (s, 10),
+    };
+    if !digits.chars().all(|x| x.is_digit(radix)) {
+        return None;
+    }
+    match digits {
+        "" if radix == 16 => Some(0),
+        x => u32::from_str_radix(x, radix).ok(),
+    }
+}
+
+/// Whether the last label is a number, even one too large for an address.
+fn ends_in_number(name: &str) -> bool {
+    let last = name.rsplit('.').next().unwrap_or_default();
+    match last.strip_prefix("0x") {
+        Some(x) => x.chars().all(|x| x.is_ascii_hexdigit()),
+        None => !last.is_empty() && last.chars().all(|x| x.is_ascii_digit()),
+    }
+}
+
+/// The ASCII form of `domain`, or `None` if it is not a valid name. A
+/// trailing root dot is kept, and so are underscores, which are common in
+/// names that are not host names.
+fn normalize_domain(domain: &str) -> Option<String> {
+    let ascii = Uts46::new().to_ascii(
+        domain.as_bytes(),
+        AsciiDenyList::URL,
+        Hyphens::Allow,
+        DnsLength::VerifyAllowRootDot,
+    );
+    ascii.ok().map(Cow::into_owned)
+}
+
+impl From<SocketAddr> for Socks5Target {
+    /// IPv4-mapped addresses, as seen by dual-stack sockets, become IPv4
+    /// addresses.
+    fn from(addr: SocketAddr) -> Self {
+        Self(Socks5Host::IpAddr(addr.ip().to_canonical()), addr.port())
     }
+}
 
-    fn parse_ipv6(data: &[u8]) -> Self {
-        debug_assert_eq!(data.len(), 18);
-        let ip = Ipv6Addr::from_octets(data[0..16].try_into().unwrap());
-        let port = u16::from_be_bytes([data[16], data[17]]);
-        Self(Socks5Host::IpAddr(IpAddr::V6(ip)), port)
+impl FromStr for Socks5Target {
+    type Err = String;
+
+    /// Parses `HOST:PORT`, with IPv6 addresses in brackets.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if let Ok(x) = s.parse::<SocketAddr>() {
+            return Ok(x.into());
+        }
+
+        let invalid = || format!("Invalid target address: {s}!");
+        let (host, port) = s.rsplit_once(':').ok_or_else(invalid)?;
+        let port = port.parse().map_err(|_| invalid())?;
+        if host.contains(':') {
+            return Err(invalid());
+        }
+        let host = normalize_host(host).ok_or_else(invalid)?;
+        Ok(Self(host, port))
     }
+}
 
-    fn parse_domain(data: &[u8]) -> Result<Self> {
-        let len = data.len();
-        debug_assert_eq!(len, 3 + data[0] as usize);
-        let domain = match String::from_utf8(data[1..len - 2].into()) {
-            Ok(s) => s,
-            Err(e) => return Err(format!("Invalid domain: {e}!").into()),
+impl FromStr for TargetPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid destination: {s}!");
+
+        // IPv6 networks with a port are bracketed: [fd00::/8]:443
+        let (pattern, port) = match s.strip_prefix('[') {
+            Some(x) => match x.split_once(']') {
+                Some((x, "")) => (x, None),
+                Some((x, port)) => (x, Some(port.strip_prefix(':').ok_or_else(invalid)?)),
+                None => return Err(invalid()),
+            },
+            None => match s.rsplit_once(':') {
+                Some((x, port)) if !x.contains(':') => (x, Some(port)),
+                _ => (s, None),
+            },
         };
-        let port = u16::from_be_bytes([data[len - 2], data[len - 1]]);
-        Ok(Self(Socks5Host::Domain(domain), port))
+        let port = match port {
+            Some(x) => Some(x.parse().map_err(|_| invalid())?),
+            None => None,
+        };
+
+        let pattern = match pattern.parse() {
+            Ok(x) => Pattern::Cidr(x),
+            Err(_) if pattern == "*" => Pattern::Any,
+            Err(_) if pattern.starts_with("*.") => {
+                let domain = normalize_domain(&pattern[2..]).ok_or_else(invalid)?;
+                Pattern::Domain(format!("*.{}", domain.trim_end_matches('.')))
+            }
+            Err(_) if !pattern.contains('/') => {
+                let domain = normalize_domain(pattern).ok_or_else(invalid)?;
+                Pattern::Domain(domain.trim_end_matches('.').to_owned())
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Self { pattern, port })
     }
+}
 
-    pub fn target_len(data: &[u8]) -> Result<usize> {
-        debug_assert!(data.len() >= 2);
-        Ok(match data[0] {
-            1 => 7,
-            4 => 19,
-            3 => 4 + data[1] as usize,
-            _ => return Err("Invalid address type!".into()),
-        })
+impl TargetPattern {
+    /// Whether `target` matches. CIDRs are matched against `addr`, the
+    /// address a domain resolved to, if known.
+    pub fn matches(&self, target: &Socks5Target, addr: Option<IpAddr>) -> bool {
+        if self.port.is_some_and(|x| x != target.1) {
+            return false;
+        }
+        match (&self.pattern, &target.0) {
+            (Pattern::Any, _) => true,
+            (Pattern::Cidr(x), Socks5Host::IpAddr(ip)) => x.contains(addr.unwrap_or(*ip)),
+            (Pattern::Cidr(x), Socks5Host::Domain(_)) => addr.is_some_and(|ip| x.contains(ip)),
+            (Pattern::Domain(x), Socks5Host::Domain(domain)) => {
+                // Fully qualified names end with the root dot.
+                let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+                match x.strip_prefix("*.") {
+                    Some(suffix) => domain
+                        .strip_suffix(suffix)
+                        .is_some_and(|x| x.ends_with('.')),
+                    None => domain == *x,
+                }
+            }
+            _ => false,
+        }
     }
 }
 
-impl TryFrom<&[u8]> for Socks5Target {
-    type Error = Error;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize(host: &str) -> Option<Socks5Host> {
+        Socks5Host::Domain(host.into()).normalize().ok()
+    }
+
+    fn domain(x: &str) -> Option<Socks5Host> {
+        Some(Socks5Host::Domain(x.into()))
+    }
+
+    fn ip(x: &str) -> Option<Socks5Host> {
+        Some(Socks5Host::IpAddr(x.parse().unwrap()))
+    }
+
+    #[test]
+    fn normalize_domains() {
+        assert_eq!(normalize("bücher.example"), domain("xn--bcher-kva.example"));
+        assert_eq!(
+            normalize("xn--bcher-kva.example"),
+            domain("xn--bcher-kva.example")
+        );
+        assert_eq!(normalize("WWW.Example.COM"), domain("www.example.com"));
+        assert_eq!(normalize("example.com."), domain("example.com."));
+        assert_eq!(
+            normalize("_dmarc.example.com"),
+            domain("_dmarc.example.com")
+        );
+        assert_eq!(normalize("1.example.com"), domain("1.example.com"));
+        assert_eq!(normalize("example.0xg"), domain("example.0xg"));
+
+        assert_eq!(normalize("a..example"), None);
+        assert_eq!(normalize(".example"), None);
+        assert_eq!(normalize(&format!("{}.example", "x".repeat(64))), None);
+        assert!(normalize(&format!("{}.example", "x".repeat(63))).is_some());
+        assert_eq!(normalize("exa\0mple.com"), None);
+        assert_eq!(normalize("exa mple.com"), None);
+        assert_eq!(normalize("example.com:80"), None);
+    }
+
+    #[test]
+    fn normalize_ip_literals() {
+        assert_eq!(normalize("127.0.0.1"), ip("127.0.0.1"));
+        assert_eq!(normalize("127.0.0.1."), ip("127.0.0.1"));
+        assert_eq!(normalize("::1"), ip("::1"));
+        assert_eq!(normalize("[::1]"), ip("::1"));
+        assert_eq!(normalize("::ffff:10.0.0.1"), ip("10.0.0.1"));
+        // Full-width digits and dots are folded first.
+        assert_eq!(normalize("１２７．０．０．１"), ip("127.0.0.1"));
+
+        // Forms the resolver takes as addresses too.
+        assert_eq!(normalize("127.1"), ip("127.0.0.1"));
+        assert_eq!(normalize("2130706433"), ip("127.0.0.1"));
+        assert_eq!(normalize("0x7f.0.0.1"), ip("127.0.0.1"));
+        assert_eq!(normalize("0X7F.1"), ip("127.0.0.1"));
+        assert_eq!(normalize("0177.0.0.1"), ip("127.0.0.1"));
+        assert_eq!(normalize("10.0x10203"), ip("10.1.2.3"));
+
+        // Names ending in a number must be addresses.
+        assert_eq!(normalize("1.2.3.256"), None);
+        assert_eq!(normalize("256.1.2.3"), None);
+        assert_eq!(normalize("1.2.3.4.5"), None);
+        assert_eq!(normalize("08.0.0.1"), None);
+        assert_eq!(normalize("4294967296"), None);
+        assert_eq!(normalize("99999999999999999999"), None);
+        assert_eq!(normalize("example.123"), None);
+    }
 
-    fn try_from(data: &[u8]) -> Result<Self> {
-        Ok(match data[0] {
-            1 => Self::parse_ipv4(&data[1..]),
-            4 => Self::parse_ipv6(&data[1..]),
-            3 => Self::parse_domain(&data[1..])?,
-            _ => return Err("Invalid address type!".into()),
-        })
+    #[test]
+    fn parse_target() {
+        let target = |x: &str| x.parse::<Socks5Target>().ok();
+        let v4 = Socks5Target(Socks5Host::IpAddr(Ipv4Addr::LOCALHOST.into()), 80);
+        assert_eq!(target("127.0.0.1:80"), Some(v4.clone()));
+        assert_eq!(target("127.1:80"), Some(v4));
+        assert_eq!(
+            target("Example.COM:443"),
+            Some(Socks5Target(Socks5Host::Domain("example.com".into()), 443))
+        );
+        assert_eq!(target("example.com"), None);
+        assert_eq!(target("::1:80"), None);
     }
 }
@@ -1,28 +1,272 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use socket2::SockRef;
+
 use super::*;
 
 pub struct Socks5TcpConnector(TcpStream);
 
 impl Socks5TcpConnector {
-    pub async fn connect(target: Socks5Target) -> Result<Self> {
-        let stream = match target.0 {
-            Socks5Host::IpAddr(x) => TcpStream::connect((x, target.1)).await?,
-            Socks5Host::Domain(x) => TcpStream::connect((x, target.1)).await?,
+    /// Connects to `target` with the dialer of the first route matching it
+    /// for the user of `session`, or directly. Domains the dialer resolves
+    /// locally are resolved first, and only the addresses the rules allow
+    /// are tried, in order.
+    pub async fn connect(
+        target: &Socks5Target,
+        config: &Config,
+        session: &Session,
+    ) -> Result<Self> {
+        let dialer = match Route::find(&config.routes, target, session.user.get()) {
+            Some(x) => &*x.tcp,
+            None => &Direct as &dyn TcpDialer,
+        };
+        let targets = config
+            .rules
+            .resolve(target, dialer.resolves_locally(), session)
+            .await?;
+
+        let mut result = Err(Error::Connect(ErrorKind::NotFound.into()));
+        for x in &targets {
+            result = dialer.dial(x).await;
+            if result.is_ok() {
+                break;
+            }
+        }
+        Ok(Self(result?))
+    }
+
+    pub async fn connect_tcp(
+        mut self,
+        stream: Socks5Stream,
+        config: &Config,
+        session: &Session,
+    ) -> Result<&'static str> {
+        let traffic = &session.traffic;
+        let (add_up, add_down) = (|x| traffic.add_up(x), |x| traffic.add_down(x));
+        let (limit_up, limit_down) = (session.limit_up(), session.limit_down());
+        let timeout = config.half_close_timeout;
+        let _active = session.metrics.tcp_sessions.enter();
+
+        let stream = match stream {
+            Socks5Stream::Tcp(x) => x,
+            #[allow(unreachable_patterns)]
+            stream => {
+                // TLS and Unix streams are relayed through userspace.
+                let (mut client_reader, mut client_writer) = tokio::io::split(stream);
+                let (upstream_reader, upstream_writer) = self.0.split();
+                let up = copy_stream(&mut client_reader, upstream_writer, add_up, limit_up);
+                let down = copy_stream(upstream_reader, &mut client_writer, add_down, limit_down);
+                let result = relay(up, down, timeout).await;
+                let stream = client_reader.unsplit(client_writer);
+                let sockets = stream.tcp().into_iter().chain([&self.0]);
+                return Ok(reset_on_error(result, sockets)?);
+            }
+        };
+        let (client, upstream) = (&stream, &self.0);
+
+        #[cfg(target_os = "linux")]
+        if let Ok(pipes) = Pipe::new().and_then(|x| Ok((x, Pipe::new()?))) {
+            let up = splice_one(client, upstream, &pipes.0, add_up, limit_up);
+            let down = splice_one(upstream, client, &pipes.1, add_down, limit_down);
+            let result = relay(up, down, timeout).await;
+            return Ok(reset_on_error(result, [client, upstream])?);
+        }
+
+        let up = copy_one(client, upstream, add_up, limit_up);
+        let down = copy_one(upstream, client, add_down, limit_down);
+        let result = relay(up, down, timeout).await;
+        Ok(reset_on_error(result, [client, upstream])?)
+    }
+}
+
+/// Like `copy_one`, for streams that can only be used through
+/// `AsyncRead`/`AsyncWrite`.
+async fn copy_stream(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+    count: impl Fn(usize),
+    limiter: &Limiter,
+) -> io::Result<()> {
+    let mut buf = vec![0; limiter.chunk(16384)];
+
+    loop {
+        let len = match reader.read(&mut buf).await {
+            Ok(0) => break,
+            Ok(x) => x,
+            // Many TLS clients close the connection without close_notify.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..len]).await?;
+        writer.flush().await?;
+        count(len);
+        limiter.consume(len).await;
+    }
+
+    writer.shutdown().await
+}
+
+/// Copies bytes from `reader` to `writer` until EOF, then shuts down the
+/// write half of `writer`. The size of every chunk written is passed to
+/// `count`, then drawn from `limiter`.
+async fn copy_one(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    count: impl Fn(usize),
+    limiter: &Limiter,
+) -> io::Result<()> {
+    let mut buf = vec![0; limiter.chunk(16384)];
+
+    loop {
+        reader.readable().await?;
+        let len = match reader.try_read(&mut buf) {
+            Ok(0) => break,
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e),
         };
-        Ok(Self(stream))
+
+        let mut data = &buf[..len];
+        while !data.is_empty() {
+            writer.writable().await?;
+            match writer.try_write(data) {
+                Ok(x) => data = &data[x..],
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        count(len);
+        limiter.consume(len).await;
+    }
+
+    shutdown_write(writer)?;
+    Ok(())
+}
+
+/// Runs both directions of a relay. Once one direction has seen EOF the
+/// other one may keep going for at most `timeout`. Returns which side
+/// closed first.
+async fn relay(
+    up: impl Future<Output = io::Result<()>>,
+    down: impl Future<Output = io::Result<()>>,
+    timeout: Option<Duration>,
+) -> io::Result<&'static str> {
+    tokio::pin!(up, down);
+
+    tokio::select! {
+        r = &mut up => match r {
+            Ok(_) => half_closed(down, timeout).await.map(|_| "client closed"),
+            Err(e) => Err(e),
+        },
+        r = &mut down => match r {
+            Ok(_) => half_closed(up, timeout).await.map(|_| "target closed"),
+            Err(e) => Err(e),
+        },
     }
+}
 
-    pub async fn connect_tcp(mut self, mut stream: TcpStream) -> Result<()> {
-        tokio::io::copy_bidirectional(&mut self.0, &mut stream).await?;
-        Ok(())
+/// If either peer reset the connection, makes sure the other one is reset
+/// as well instead of being closed gracefully.
+fn reset_on_error<'a, T>(
+    result: io::Result<T>,
+    sockets: impl IntoIterator<Item = &'a TcpStream>,
+) -> io::Result<T> {
+    if let Err(e) = &result
+        && matches!(e.kind(), ErrorKind::ConnectionReset | ErrorKind::BrokenPipe)
+    {
+        // With a zero linger timeout, dropping the sockets sends RST.
+        for x in sockets {
+            let _ = SockRef::from(x).set_linger(Some(Duration::ZERO));
+        }
+    }
+
+    result
+}
+
+async fn half_closed(
+    remaining: impl Future<Output = io::Result<()>>,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    match timeout {
+        Some(x) => match tokio::time::timeout(x, remaining).await {
+            Ok(r) => r,
+            Err(_) => Err(io::Error::new(
+                ErrorKind::TimedOut,
+                "Half-closed connection timed out!",
+            )),
+        },
+        None => remaining.await,
     }
 }
 
 impl Socks5Acceptor {
-    pub async fn connect(mut self, target: Socks5Target) -> Result<()> {
-        eprintln!("{} -> {}", self.peer_addr(), target);
-        let connector = Socks5TcpConnector::connect(target).await?;
-        self.connected(self.stream.local_addr()?).await?;
+    pub async fn connect(mut self, target: Socks5Target) -> Result<&'static str> {
+        info!(conn = self.session.id; "{} -> {}", self.session.client(), target);
+        let connector = match self.dial(&target).await {
+            Ok(x) => x,
+            Err(e) => {
+                let _ = self.closed(&e).await;
+                return Err(e);
+            }
+        };
+        // Clients on a Unix socket get the address used to reach the target.
+        let local_addr = match self.stream.local_addr() {
+            Ok(x) => x,
+            Err(_) => connector.0.local_addr()?,
+        };
+        self.connected(local_addr).await?;
+
+        self.shape();
+        connector
+            .connect_tcp(self.stream, &self.config, &self.session)
+            .await
+    }
+
+    /// Relays the connection to a fixed `target` without any SOCKS
+    /// negotiation, as for transparent proxying and tunnels.
+    pub async fn forward(
+        self,
+        target: Socks5Target,
+        command: &'static str,
+    ) -> Result<&'static str> {
+        let _ = self.session.target.set(target.clone());
+        let _ = self.session.command.set(command);
+        let kind = command.to_ascii_lowercase();
+        info!(conn = self.session.id; "{} -> {} ({kind})", self.session.client(), target);
+
+        let _permit = self.admit(false)?;
+        Metrics::inc(&self.session.metrics.accepted);
+        let connector = self.dial(&target).await?;
+        self.shape();
+        connector
+            .connect_tcp(self.stream, &self.config, &self.session)
+            .await
+    }
 
-        connector.connect_tcp(self.stream).await
+    /// Connects to `target` on behalf of the client, sending a PROXY
+    /// protocol header first if configured.
+    pub async fn dial(&self, target: &Socks5Target) -> Result<Socks5TcpConnector> {
+        let metrics = &self.session.metrics;
+        let start = Instant::now();
+        let mut connector =
+            match Socks5TcpConnector::connect(target, &self.config, &self.session).await {
+                Ok(x) => x,
+                Err(e) => {
+                    metrics.reject(match e {
+                        Error::Denied(_) => Reject::Rule,
+                        _ => Reject::ConnectFailed,
+                    });
+                    return Err(e);
+                }
+            };
+        metrics.connect_latency.observe(start.elapsed());
+        let destination = connector.0.peer_addr()?;
+        if let Some(header) =
+            proxy_protocol::outbound_header(&self.config, &self.session, target, destination)
+        {
+            connector.0.write_all(&header).await?;
+        }
+        Ok(connector)
     }
 }
@@ -0,0 +1,203 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{
+    CertificateDer, CertificateRevocationListDer, PrivateKeyDer,
+};
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use super::*;
+
+#[derive(Clone, Debug)]
+pub struct TlsOptions {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Certificates selected by the SNI server name: (name, cert, key).
+    pub sni: Vec<(String, PathBuf, PathBuf)>,
+    /// CA bundle used to verify client certificates.
+    pub client_ca: Option<PathBuf>,
+    pub require_client_cert: bool,
+    /// Certificate revocation lists checked against client certificates.
+    pub crls: Vec<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CertIdentity {
+    /// The subject common name.
+    #[default]
+    Cn,
+    /// The first DNS name, email address or URI subject alternative name.
+    San,
+    /// The hex encoded SHA-256 digest of the certificate.
+    Fingerprint,
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    names: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let Some(name) = hello.server_name() else {
+            return Some(self.default.clone());
+        };
+        let name = name.to_ascii_lowercase();
+        let wildcard = name.split_once('.').map(|(_, x)| format!("*.{x}"));
+
+        self.names
+            .get(&name)
+            .or_else(|| self.names.get(&wildcard?))
+            .or(Some(&self.default))
+            .cloned()
+    }
+}
+
+impl FromStr for CertIdentity {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "cn" => CertIdentity::Cn,
+            "san" => CertIdentity::San,
+            "fingerprint" => CertIdentity::Fingerprint,
+            _ => return Err(format!("Invalid certificate identity: {s}!")),
+        })
+    }
+}
+
+impl CertIdentity {
+    /// The user a verified client certificate maps to.
+    pub fn of(&self, cert: &CertificateDer<'_>) -> Option<String> {
+        if *self == CertIdentity::Fingerprint {
+            let digest = ::ring::digest::digest(&::ring::digest::SHA256, cert);
+            let mut hex = String::with_capacity(64);
+            for x in digest.as_ref() {
+                let _ = write!(hex, "{x:02x}");
+            }
+            return Some(hex);
+        }
+
+        let (_, cert) = X509Certificate::from_der(cert).ok()?;
+        match self {
+            CertIdentity::Cn => {
+                let cn = cert.subject().iter_common_name().next()?;
+                cn.as_str().ok().map(str::to_owned)
+            }
+            _ => {
+                let san = cert.subject_alternative_name().ok()??;
+                san.value.general_names.iter().find_map(|x| match x {
+                    GeneralName::DNSName(x) | GeneralName::RFC822Name(x) | GeneralName::URI(x) => {
+                        Some(x.to_string())
+                    }
+                    _ => None,
+                })
+            }
+        }
+    }
+}
+
+impl TlsOptions {
+    pub fn parse_sni(s: &str) -> std::result::Result<(String, PathBuf, PathBuf), String> {
+        let (name, files) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected NAME=CERT,KEY: {s}"))?;
+        let (cert, key) = files
+            .split_once(',')
+            .ok_or_else(|| format!("Expected NAME=CERT,KEY: {s}"))?;
+        Ok((name.to_ascii_lowercase(), cert.into(), key.into()))
+    }
+
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let provider = Arc::new(ring::default_provider());
+
+        let resolver = SniResolver {
+            default: load_certified_key(&provider, &self.cert, &self.key)?,
+            names: self
+                .sni
+                .iter()
+                .map(|(name, cert, key)| {
+                    Ok((name.clone(), load_certified_key(&provider, cert, key)?))
+                })
+                .collect::<Result<_>>()?,
+        };
+
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match &self.client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in CertificateDer::pem_file_iter(ca)? {
+                    roots.add(cert?)?;
+                }
+                let mut crls = Vec::new();
+                for crl in &self.crls {
+                    for x in CertificateRevocationListDer::pem_file_iter(crl)? {
+                        crls.push(x?);
+                    }
+                }
+                let verifier =
+                    WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                        .with_crls(crls)
+                        .only_check_end_entity_revocation();
+                let verifier = if self.require_client_cert {
+                    verifier.build()?
+                } else {
+                    verifier.allow_unauthenticated().build()?
+                };
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder.with_cert_resolver(Arc::new(resolver));
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn load_certified_key(
+    provider: &CryptoProvider,
+    cert: &Path,
+    key: &Path,
+) -> Result<Arc<CertifiedKey>> {
+    let certs = CertificateDer::pem_file_iter(cert)?.collect::<std::result::Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(format!("No certificate found in {}!", cert.display()).into());
+    }
+    let key = provider
+        .key_provider
+        .load_private_key(PrivateKeyDer::from_pem_file(key)?)?;
+    Ok(Arc::new(CertifiedKey::new(certs, key)))
+}
+
+impl Socks5Acceptor {
+    /// Performs the TLS handshake on the accepted connection. A verified
+    /// client certificate, if any, becomes the user of the session.
+    pub async fn accept_tls(mut self, tls: &TlsAcceptor, identity: CertIdentity) -> Result<Self> {
+        let Socks5Stream::Tcp(stream) = self.stream else {
+            return Ok(self);
+        };
+
+        let stream = tls.accept(stream).await?;
+        let identity = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|x| x.first())
+            .and_then(|x| identity.of(x));
+        if let Some(x) = identity {
+            let _ = self.session.user.set(Identity::from(x));
+        }
+
+        self.stream = Socks5Stream::Tls(Box::new(stream));
+        Ok(self)
+    }
+}
@@ -0,0 +1,264 @@
+use std::mem::{size_of, size_of_val, zeroed};
+use std::os::fd::{AsRawFd, RawFd};
+use std::str::FromStr;
+
+use socket2::SockAddr;
+use tokio::io::Interest;
+
+use super::*;
+use crate::udp::{Flows, UdpFlow};
+
+/// How connections are steered to a transparent listener.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TransparentMode {
+    /// iptables/nftables REDIRECT: the original destination is kept by
+    /// conntrack (SO_ORIGINAL_DST).
+    Redirect,
+    /// TPROXY: the connection keeps its original destination as the local
+    /// address, and UDP is supported as well.
+    Tproxy,
+}
+
+impl FromStr for TransparentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "redirect" => TransparentMode::Redirect,
+            "tproxy" => TransparentMode::Tproxy,
+            _ => return Err(format!("Invalid transparent proxy mode: {s}!")),
+        })
+    }
+}
+
+fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as _,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Sets IP_TRANSPARENT, which allows accepting connections and binding to
+/// addresses that are not local. Requires CAP_NET_ADMIN.
+pub fn set_transparent(socket: &impl AsRawFd, v6: bool) -> io::Result<()> {
+    match v6 {
+        true => setsockopt(socket.as_raw_fd(), libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+        false => setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_TRANSPARENT),
+    }
+}
+
+/// The destination a redirected connection was originally addressed to.
+fn original_dst(stream: &TcpStream, mode: TransparentMode) -> io::Result<SocketAddr> {
+    let local_addr = stream.local_addr()?;
+    if mode == TransparentMode::Tproxy {
+        return Ok(local_addr);
+    }
+
+    let (level, name) = match local_addr.ip().to_canonical() {
+        IpAddr::V4(_) => (libc::SOL_IP, libc::SO_ORIGINAL_DST),
+        IpAddr::V6(_) => (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST),
+    };
+    let (_, addr) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            if libc::getsockopt(stream.as_raw_fd(), level, name, storage.cast(), len) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    }?;
+    addr.as_socket()
+        .ok_or_else(|| io::Error::from(ErrorKind::InvalidData))
+}
+
+fn sockaddr_in(addr: &libc::sockaddr_in) -> SocketAddr {
+    let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+    SocketAddr::new(ip.into(), u16::from_be(addr.sin_port))
+}
+
+fn sockaddr_in6(addr: &libc::sockaddr_in6) -> SocketAddr {
+    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+    SocketAddr::new(ip.to_canonical(), u16::from_be(addr.sin6_port))
+}
+
+/// Receives a datagram along with its source and original destination
+/// (IP_ORIGDSTADDR).
+fn recv_orig_dst(
+    socket: &UdpSocket,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, SocketAddr)> {
+    let mut name: libc::sockaddr_storage = unsafe { zeroed() };
+    let mut control = [0u64; 16];
+    let mut iovec = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    let mut hdr: libc::msghdr = unsafe { zeroed() };
+    hdr.msg_name = (&mut name as *mut libc::sockaddr_storage).cast();
+    hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as _;
+    hdr.msg_iov = &mut iovec;
+    hdr.msg_iovlen = 1;
+    hdr.msg_control = control.as_mut_ptr().cast();
+    hdr.msg_controllen = size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut hdr, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let source = match name.ss_family as libc::c_int {
+        libc::AF_INET => sockaddr_in(unsafe { &*(&name as *const _ as *const libc::sockaddr_in) }),
+        libc::AF_INET6 => {
+            sockaddr_in6(unsafe { &*(&name as *const _ as *const libc::sockaddr_in6) })
+        }
+        _ => return Err(io::Error::from(ErrorKind::InvalidData)),
+    };
+
+    let mut destination = None;
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&hdr);
+        while !cmsg.is_null() {
+            let data = libc::CMSG_DATA(cmsg);
+            destination = match ((*cmsg).cmsg_level, (*cmsg).cmsg_type) {
+                (libc::SOL_IP, libc::IP_ORIGDSTADDR) => Some(sockaddr_in(
+                    &(data as *const libc::sockaddr_in).read_unaligned(),
+                )),
+                (libc::SOL_IPV6, libc::IPV6_ORIGDSTADDR) => Some(sockaddr_in6(
+                    &(data as *const libc::sockaddr_in6).read_unaligned(),
+                )),
+                _ => destination,
+            };
+            cmsg = libc::CMSG_NXTHDR(&hdr, cmsg);
+        }
+    }
+
+    let destination = destination.ok_or_else(|| io::Error::from(ErrorKind::InvalidData))?;
+    Ok((len as usize, source, destination))
+}
+
+/// Binds a UDP socket that receives TPROXY'd datagrams for any destination.
+pub fn bind_udp(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    set_transparent(&socket, addr.is_ipv6())?;
+    if addr.is_ipv6() {
+        setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IPV6_RECVORIGDSTADDR,
+        )?;
+        // Dual-stack sockets receive IPv4 datagrams too.
+        let _ = setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_RECVORIGDSTADDR);
+    } else {
+        setsockopt(socket.as_raw_fd(), libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    }
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Binds a socket to the original destination `local` of a flow and
+/// connects it to the client, so that replies appear to come from the
+/// target.
+fn bind_reply(local: SocketAddr, client: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+    set_transparent(&socket, local.is_ipv6())?;
+    socket.bind(&local.into())?;
+    socket.connect(&client.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Relays TPROXY'd UDP datagrams, one flow per client and destination.
+/// The socket counts as a listener of its own for bandwidth limits.
+pub async fn serve_udp(
+    socket: UdpSocket,
+    config: Arc<Config>,
+    metrics: Arc<Metrics>,
+    sessions: Arc<Registry>,
+) -> Result<()> {
+    info!("Transparent UDP listening on: {}", socket.local_addr()?);
+
+    let flows = Flows::new(config, metrics.clone(), sessions);
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, client, target) = match socket
+            .async_io(Interest::READABLE, || recv_orig_dst(&socket, &mut buf))
+            .await
+        {
+            Ok(x) => x,
+            Err(e) if e.kind() == ErrorKind::InvalidData => continue,
+            Err(e) => return Err(e.into()),
+        };
+        // Datagrams we sent to a destination that is routed back here.
+        if client.ip() == target.ip() {
+            Metrics::inc(&metrics.udp_drops);
+            continue;
+        }
+
+        let flow = match flows.get(&(client, target)) {
+            Some(x) => x,
+            None => match start_flow(client, target, &flows).await {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!("{client} => {target} (UDP): {e}");
+                    Metrics::inc(&metrics.udp_drops);
+                    continue;
+                }
+            },
+        };
+        flow.send(&buf[..len]).await;
+    }
+}
+
+/// Sets up a new flow, which reaches its target like SOCKS clients do.
+/// Replies are sent from a socket bound to the original destination, so
+/// that they appear to come from it.
+async fn start_flow(client: SocketAddr, target: SocketAddr, flows: &Flows) -> Result<Arc<UdpFlow>> {
+    let key = (client, target);
+    let target = Socks5Target::from(target);
+    let session = flows.session(client, target.clone(), "transparent")?;
+    let upstream = flows.dial(&session.0, &target).await?;
+    let reply = Arc::new(bind_reply(key.1, client)?);
+    Ok(flows.spawn(session, upstream, (reply, None), key))
+}
+
+impl Socks5Acceptor {
+    /// Relays a redirected connection to its original destination. The
+    /// client does not speak SOCKS at all.
+    pub async fn accept_transparent(self, mode: TransparentMode) -> Result<&'static str> {
+        let stream = self.stream.tcp().ok_or("Not a TCP connection!")?;
+        let destination = match original_dst(stream, mode) {
+            Ok(x) => x,
+            // No conntrack entry for the connection.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.session.metrics.reject(Reject::Protocol);
+                return Err(Error::Protocol("Connection was not redirected!".into()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        // A connection that was not redirected, or one we made ourselves to
+        // such a destination, would loop.
+        if destination == stream.local_addr()? && mode == TransparentMode::Redirect
+            || destination.ip().to_canonical() == stream.peer_addr()?.ip().to_canonical()
+        {
+            self.session.metrics.reject(Reject::Protocol);
+            return Err(Error::Protocol("Connection was not redirected!".into()));
+        }
+
+        let target = Socks5Target(
+            Socks5Host::IpAddr(destination.ip().to_canonical()),
+            destination.port(),
+        );
+        self.forward(target, "TRANSPARENT").await
+    }
+}
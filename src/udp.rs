@@ -1,17 +1,49 @@
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+
 use super::*;
 
+/// UDP flows without traffic in either direction for this long are closed.
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A UDP flow outside of SOCKS: its client and the destination it sent to.
+type FlowKey = (SocketAddr, SocketAddr);
+
+/// The UDP flows relayed for one socket outside of SOCKS, by client and
+/// destination, and what they share.
+pub struct Flows {
+    live: Arc<Mutex<HashMap<FlowKey, Arc<UdpFlow>>>>,
+    config: Arc<Config>,
+    /// The bandwidth limit of the socket, which counts as a listener.
+    buckets: Option<Buckets>,
+    metrics: Arc<Metrics>,
+    sessions: Arc<Registry>,
+}
+
 pub struct Socks5UdpClient {
     pub udp_socket: UdpSocket,
     pub client_addr: SocketAddr,
 }
 
+/// The direct [`UdpSession`], sending from one socket to any target.
+pub struct DirectUdp {
+    udp_socket: UdpSocket,
+    resolver: tokio::sync::Mutex<Resolver>,
+}
+
 pub struct Socks5UdpForwarder {
-    ipv4_only: bool,
-    udp_socket: Option<UdpSocket>,
-    hosts: Option<HashMap<String, IpAddr>>,
+    udp_socket: UdpSocket,
+    resolver: Resolver,
     targets: HashSet<Socks5Target>,
 }
 
+/// Resolves targets to addresses of the family of a socket, caching
+/// domains.
+pub struct Resolver {
+    ipv4_only: bool,
+    hosts: HashMap<String, IpAddr>,
+}
+
 impl Socks5UdpClient {
     pub fn new(udp_socket: UdpSocket, client_addr: SocketAddr) -> Self {
         Self {
@@ -33,57 +65,46 @@ impl Socks5UdpForwarder {
             ipv4_only = false;
             UdpSocket::from_std(socket.into())
         })() {
-            Some(socket)
+            socket
         } else {
             let socket = Socket::new_raw(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
             socket.set_nonblocking(true)?;
             socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into())?;
-            Some(UdpSocket::from_std(socket.into())?)
+            UdpSocket::from_std(socket.into())?
         };
 
         Ok(Self {
-            ipv4_only,
             udp_socket,
-            hosts: None,
+            resolver: Resolver::new(ipv4_only),
             targets: HashSet::new(),
         })
     }
 
-    pub fn ipv4_only(&self) -> bool {
-        self.ipv4_only
+    /// Connects the socket to a fixed `target`, so that only its replies
+    /// are received.
+    pub async fn connect(mut self, target: &Socks5Target) -> Result<DirectUdp> {
+        let ip = self.resolver.resolve(&target.0, None).await;
+        let ip = ip.ok_or_else(|| unresolvable(target))?;
+        self.udp_socket.connect((ip, target.1)).await?;
+        Ok(self.into_session())
     }
 
-    pub async fn lookup_host(&mut self, host: &str) -> Option<IpAddr> {
-        let hosts = self.hosts.get_or_insert_default();
-
-        if let Some(x) = hosts.get(host) {
-            if !x.is_unspecified() {
-                return Some(*x);
-            } else {
-                return None;
-            }
-        } else {
-            if let Ok(mut x) = tokio::net::lookup_host((host, 0)).await {
-                for x in x.by_ref() {
-                    if self.ipv4_only && x.is_ipv6() {
-                        continue;
-                    }
-                    let mut ip = x.ip();
-                    if !self.ipv4_only
-                        && let IpAddr::V4(x) = ip
-                    {
-                        ip = x.to_ipv6_mapped().into()
-                    }
-                    hosts.insert(host.into(), ip);
-                    return Some(ip);
-                }
-            }
-            hosts.insert(host.into(), (Ipv4Addr::UNSPECIFIED).into());
+    /// Turns the forwarder into a [`UdpSession`] for relaying one datagram
+    /// at a time.
+    pub fn into_session(self) -> DirectUdp {
+        DirectUdp {
+            udp_socket: self.udp_socket,
+            resolver: tokio::sync::Mutex::new(self.resolver),
         }
-        None
     }
 
-    pub async fn forward_udp(mut self, client: Socks5UdpClient) -> Result<()> {
+    pub async fn forward_udp(
+        self,
+        client: Socks5UdpClient,
+        rules: &RuleSet,
+        session: &Session,
+    ) -> Result<()> {
+        let traffic = &session.traffic;
         let udp_socket = client.udp_socket;
         let client_addr = client.client_addr;
         let local_addr = udp_socket.local_addr()?;
@@ -96,46 +117,58 @@ impl Socks5UdpForwarder {
         let (mut len, from) = udp_socket.recv_from(&mut buf).await?;
         if udp_socket.peer_addr().is_err() {
             if from.ip() != client_addr.ip() {
-                return Err(format!("Invalid udp client: {from}!").into());
+                return Err(Error::Protocol(format!("Invalid udp client: {from}!")));
             }
             udp_socket.connect(from).await?;
         }
-        println!("{from} <> {local_addr} (UDP)");
+        debug!(conn = session.id; "{from} <> {local_addr} (UDP)");
 
         let (client_receiver, client_sender) = &mut udp_socket.split();
-        let (upstream_receiver, upstream_sender) =
-            &mut self.udp_socket.take().into_result()?.split();
+        let Self {
+            udp_socket: upstream,
+            mut resolver,
+            mut targets,
+        } = self;
+        let (upstream_receiver, upstream_sender) = &mut upstream.split();
 
         let t1 = async {
             loop {
-                if &buf[..3] != b"\0\0\0" {
-                    return Err("Invalid socks5 udp request!".into());
-                }
-                let offset = Socks5Target::target_len(&buf[3..])?;
-                let target = Socks5Target::try_from(&buf[3..3 + offset])?;
-                if self.targets.insert(target.clone()) {
-                    println!("{from} -> {target} (UDP)");
+                // Fragments are not reassembled, like undecodable datagrams
+                // they are dropped.
+                let relayed = match UdpHeader::decode(&buf[..len]) {
+                    Ok((header, offset)) if header.frag == 0 => {
+                        let mut target = header.target;
+                        match target.0.normalize() {
+                            Ok(x) => {
+                                target.0 = x;
+                                Some((target, offset))
+                            }
+                            Err(_) => None,
+                        }
+                    }
+                    _ => None,
+                };
+                let Some((target, offset)) = relayed else {
+                    Metrics::inc(&session.metrics.udp_drops);
+                    len = client_receiver.recv(&mut buf).await?;
+                    continue;
+                };
+                if targets.insert(target.clone()) {
+                    info!(conn = session.id; "{from} -> {target} (UDP)");
                 }
 
-                let data = &buf[3 + offset..len];
-                let ip = match target.0 {
-                    Socks5Host::IpAddr(IpAddr::V4(x)) if !self.ipv4_only() => {
-                        Some(x.to_ipv6_mapped().into())
+                let ip = resolver.resolve(&target.0, Some(&session.metrics)).await;
+                if let Some(ip) = ip.filter(|x| rules.allows(&target, Some(*x), session)) {
+                    let data = &buf[offset..len];
+                    traffic.packet_up(data.len());
+                    session.limit_up().consume(data.len()).await;
+                    match upstream_sender.send_to(data, (ip, target.1)).await {
+                        Ok(_) => {}
+                        Err(e) if is_unreachable(&e) => Metrics::inc(&session.metrics.udp_drops),
+                        Err(e) => Err(e)?,
                     }
-                    Socks5Host::IpAddr(IpAddr::V6(_)) if self.ipv4_only() => None,
-                    Socks5Host::IpAddr(x) => Some(x),
-                    Socks5Host::Domain(x) => self.lookup_host(&x).await,
-                };
-                if let Some(ip) = ip {
-                    use ErrorKind::*;
-                    upstream_sender
-                        .send_to(data, (ip, target.1))
-                        .await
-                        .or_else(|e| match e.kind() {
-                            ConnectionRefused | ConnectionReset | NetworkUnreachable
-                            | HostUnreachable | ConnectionAborted => Ok(0),
-                            _ => Err(e),
-                        })?;
+                } else {
+                    Metrics::inc(&session.metrics.udp_drops);
                 }
 
                 len = client_receiver.recv(&mut buf).await?;
@@ -144,21 +177,19 @@ impl Socks5UdpForwarder {
 
         let t2 = async {
             let mut buf = vec![0; 1472];
-            let mut header = (b"\x00\x00\x00").to_vec();
+            let mut header = Vec::new();
 
             loop {
-                use ErrorKind::*;
                 let (len, from) = match upstream_receiver.recv_from(&mut buf).await {
                     Ok(x) => x,
-                    Err(e) => match e.kind() {
-                        ConnectionRefused | ConnectionReset | NetworkUnreachable
-                        | HostUnreachable | ConnectionAborted => continue,
-                        _ => Err(e)?,
-                    },
+                    Err(e) if is_unreachable(&e) => continue,
+                    Err(e) => Err(e)?,
                 };
-                header.truncate(3);
-                header.put_socks5_addr(from);
+                traffic.packet_down(len);
+                header.clear();
+                UdpHeader::new(from.into()).encode(&mut header)?;
 
+                session.limit_down().consume(len).await;
                 let data = [IoSlice::new(&header), IoSlice::new(&buf[..len])];
                 client_sender.send_vectored(&data).await?;
             }
@@ -175,36 +206,443 @@ impl Socks5UdpForwarder {
     }
 }
 
+impl Resolver {
+    pub fn new(ipv4_only: bool) -> Self {
+        Self {
+            ipv4_only,
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Looks up `host`, counting cache hits and misses in `metrics` if
+    /// given.
+    pub async fn lookup_host(&mut self, host: &str, metrics: Option<&Metrics>) -> Option<IpAddr> {
+        let hosts = &mut self.hosts;
+        let cached = hosts.get(host).copied();
+        if let Some(metrics) = metrics {
+            Metrics::inc(match cached {
+                Some(_) => &metrics.dns_cache_hits,
+                None => &metrics.dns_cache_misses,
+            });
+        }
+        if let Some(x) = cached {
+            if !x.is_unspecified() {
+                return Some(x);
+            } else {
+                return None;
+            }
+        } else {
+            if let Ok(mut x) = tokio::net::lookup_host((host, 0)).await {
+                for x in x.by_ref() {
+                    if self.ipv4_only && x.is_ipv6() {
+                        continue;
+                    }
+                    let mut ip = x.ip();
+                    if !self.ipv4_only
+                        && let IpAddr::V4(x) = ip
+                    {
+                        ip = x.to_ipv6_mapped().into()
+                    }
+                    hosts.insert(host.into(), ip);
+                    return Some(ip);
+                }
+            }
+            hosts.insert(host.into(), (Ipv4Addr::UNSPECIFIED).into());
+        }
+        None
+    }
+
+    /// The address to send datagrams for `host` to, in the family of the
+    /// socket.
+    pub async fn resolve(
+        &mut self,
+        host: &Socks5Host,
+        metrics: Option<&Metrics>,
+    ) -> Option<IpAddr> {
+        match host {
+            Socks5Host::IpAddr(IpAddr::V4(x)) if !self.ipv4_only => Some(x.to_ipv6_mapped().into()),
+            Socks5Host::IpAddr(IpAddr::V6(_)) if self.ipv4_only => None,
+            Socks5Host::IpAddr(x) => Some(*x),
+            Socks5Host::Domain(x) => self.lookup_host(x, metrics).await,
+        }
+    }
+}
+
+impl UdpSession for DirectUdp {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: &'a Socks5Target) -> DialFuture<'a, ()> {
+        Box::pin(async move {
+            let ip = self.resolver.lock().await.resolve(&target.0, None).await;
+            let ip = ip.ok_or_else(|| unresolvable(target))?;
+            self.udp_socket.send_to(buf, (ip, target.1)).await?;
+            Ok(())
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> DialFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move { Ok(self.udp_socket.recv_from(buf).await?) })
+    }
+}
+
+/// Relays an association whose datagrams may be routed through UDP
+/// dialers, one datagram at a time. Each route gets its own session, bound
+/// on first use; targets without a UDP dialer are relayed directly. Like
+/// connections, domains resolved here are checked against the rules by
+/// their address.
+async fn forward_routed(
+    client: Socks5UdpClient,
+    routes: &[Route],
+    rules: &RuleSet,
+    session: &Arc<Session>,
+) -> Result<()> {
+    let traffic = &session.traffic;
+    let udp_socket = Arc::new(client.udp_socket);
+    let client_addr = client.client_addr;
+    if client_addr.port() != 0 {
+        udp_socket.connect(client_addr).await?;
+    }
+
+    let mut sessions: HashMap<Option<usize>, Arc<dyn UdpSession>> = HashMap::new();
+    let mut resolver = Resolver::new(false);
+    let mut targets = HashSet::new();
+    let mut replies = tokio::task::JoinSet::new();
+    let mut buf = vec![0; 65536];
+    loop {
+        let (len, from) = tokio::select! {
+            r = udp_socket.recv_from(&mut buf) => r?,
+            Some(r) = replies.join_next() => match r {
+                Ok(r) => return r,
+                Err(e) => return Err(e.into()),
+            },
+        };
+        if udp_socket.peer_addr().is_err() {
+            if from.ip() != client_addr.ip() {
+                return Err(Error::Protocol(format!("Invalid udp client: {from}!")));
+            }
+            udp_socket.connect(from).await?;
+        }
+
+        let (header, offset) = match UdpHeader::decode(&buf[..len]) {
+            Ok(x) if x.0.frag == 0 => x,
+            _ => {
+                Metrics::inc(&session.metrics.udp_drops);
+                continue;
+            }
+        };
+        let mut target = header.target;
+        target.0 = match target.0.normalize() {
+            Ok(x) => x,
+            Err(_) => {
+                Metrics::inc(&session.metrics.udp_drops);
+                continue;
+            }
+        };
+
+        // Routes without a UDP dialer share the direct session.
+        let route = routes
+            .iter()
+            .enumerate()
+            .find(|(_, x)| x.matches(&target, session.user.get()))
+            .and_then(|(i, x)| Some((i, x.udp.as_ref()?)));
+        // Domains resolved here are checked and sent by their address.
+        let addr = match &target.0 {
+            Socks5Host::Domain(_) if route.is_none_or(|(_, x)| x.resolves_locally()) => {
+                match resolver.resolve(&target.0, Some(&session.metrics)).await {
+                    Some(x) => Some(x.to_canonical()),
+                    None => {
+                        Metrics::inc(&session.metrics.udp_drops);
+                        continue;
+                    }
+                }
+            }
+            _ => None,
+        };
+        if !rules.allows(&target, addr, session) {
+            Metrics::inc(&session.metrics.udp_drops);
+            continue;
+        }
+
+        let outbound = match sessions.get(&route.map(|x| x.0)) {
+            Some(x) => x.clone(),
+            None => {
+                let dialer = match route {
+                    Some((_, x)) => x.clone(),
+                    None => Arc::new(Direct) as Arc<dyn UdpDialer>,
+                };
+                let outbound: Arc<dyn UdpSession> = Arc::from(dialer.bind().await?);
+                sessions.insert(route.map(|x| x.0), outbound.clone());
+                let reply = (outbound.clone(), udp_socket.clone(), session.clone());
+                replies.spawn(relay_replies(reply.0, reply.1, reply.2));
+                outbound
+            }
+        };
+        if targets.insert(target.clone()) {
+            info!(conn = session.id; "{from} -> {target} (UDP)");
+        }
+        if let Some(ip) = addr {
+            target.0 = Socks5Host::IpAddr(ip);
+        }
+
+        session.limit_up().consume(len - offset).await;
+        match outbound.send_to(&buf[offset..len], &target).await {
+            Ok(()) => traffic.packet_up(len - offset),
+            Err(_) => Metrics::inc(&session.metrics.udp_drops),
+        }
+    }
+}
+
+/// Relays the datagrams `outbound` receives to the client.
+async fn relay_replies(
+    outbound: Arc<dyn UdpSession>,
+    udp_socket: Arc<UdpSocket>,
+    session: Arc<Session>,
+) -> Result<()> {
+    let mut buf = vec![0; 65536];
+    let mut packet = Vec::with_capacity(65536);
+    loop {
+        let (len, from) = match outbound.recv_from(&mut buf).await {
+            Ok(x) => x,
+            Err(Error::Io(e)) if is_unreachable(&e) => continue,
+            Err(e) => return Err(e),
+        };
+        packet.clear();
+        UdpHeader::new(from.into()).encode(&mut packet)?;
+        packet.extend_from_slice(&buf[..len]);
+        session.limit_down().consume(len).await;
+        udp_socket.send(&packet).await?;
+        session.traffic.packet_down(len);
+    }
+}
+
+/// Fails like a connection to a domain that does not resolve.
+fn unresolvable(target: &Socks5Target) -> Error {
+    let e = io::Error::new(ErrorKind::NotFound, format!("Could not resolve {target}!"));
+    Error::Connect(e)
+}
+
+fn is_unreachable(e: &io::Error) -> bool {
+    use ErrorKind::*;
+    matches!(
+        e.kind(),
+        ConnectionRefused
+            | ConnectionReset
+            | NetworkUnreachable
+            | HostUnreachable
+            | ConnectionAborted
+    )
+}
+
+/// A UDP relay between one client and one target outside of SOCKS, as
+/// used by transparent proxying and tunnels.
+pub struct UdpFlow {
+    session: Arc<Session>,
+    upstream: Box<dyn UdpSession>,
+    /// Where datagrams are sent through `upstream`.
+    target: Socks5Target,
+    reply: Arc<UdpSocket>,
+    /// Where replies go, unless `reply` is connected to the client.
+    reply_to: Option<SocketAddr>,
+    _permit: Option<Permit>,
+}
+
+impl Flows {
+    pub fn new(config: Arc<Config>, metrics: Arc<Metrics>, sessions: Arc<Registry>) -> Self {
+        Self {
+            live: Arc::default(),
+            buckets: config.shaper.as_ref().and_then(|x| x.listener()),
+            config,
+            metrics,
+            sessions,
+        }
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<Arc<UdpFlow>> {
+        self.live.lock().unwrap().get(key).cloned()
+    }
+
+    /// Creates the session of a new flow, `kind` being e.g. "transparent",
+    /// once the session limits admit it as a UDP session of the client. It
+    /// is shaped by the shaper if any, with the buckets of the socket.
+    pub fn session(
+        &self,
+        client: SocketAddr,
+        target: Socks5Target,
+        kind: &str,
+    ) -> Result<(Arc<Session>, Option<Permit>)> {
+        let session = Arc::new(Session::new(Socks5Peer::Inet(client), self.metrics.clone()));
+        let _ = session.command.set("UDP");
+        let _ = session.target.set(target.clone());
+        Metrics::inc(&self.metrics.connections);
+        let permit = match &self.config.admission {
+            Some(x) => Some(x.admit(&session, true).inspect_err(|_| {
+                self.metrics.reject(Reject::Limit);
+            })?),
+            None => None,
+        };
+        info!(conn = session.id; "{client} => {target} ({kind} UDP)");
+        if let Some(shaper) = &self.config.shaper {
+            let limiters = shaper.session(self.buckets.as_ref(), &session);
+            let _ = session.limiters.set(limiters);
+        }
+        Metrics::inc(&self.metrics.accepted);
+        Ok((session, permit))
+    }
+
+    /// Opens the outbound side of a flow to `target`, through the UDP
+    /// dialer of the first route matching it, or with a socket connected to
+    /// it. As for connections, domains resolved here are checked against
+    /// the rules by their addresses; the flow then sends to the first one
+    /// allowed, which is returned with the session.
+    pub async fn dial(
+        &self,
+        session: &Session,
+        target: &Socks5Target,
+    ) -> Result<(Box<dyn UdpSession>, Socks5Target)> {
+        let config = &self.config;
+        let route = Route::find(&config.routes, target, session.user.get());
+        let dialer = route.and_then(|x| x.udp.as_ref());
+        let resolve = dialer.is_none_or(|x| x.resolves_locally());
+        let target = match config.rules.resolve(target, resolve, session).await {
+            Ok(mut x) => x.remove(0),
+            Err(e) => {
+                if let Error::Denied(_) = e {
+                    self.metrics.reject(Reject::Rule);
+                }
+                return Err(e);
+            }
+        };
+        let upstream = match dialer {
+            Some(x) => x.bind().await?,
+            None => Box::new(Socks5UdpForwarder::bind()?.connect(&target).await?),
+        };
+        Ok((upstream, target))
+    }
+
+    /// Adds a flow under `key` and spawns the task that relays replies from
+    /// `upstream` until the flow is idle. Replies go out through `reply`,
+    /// to `reply_to` unless it is connected to the client.
+    pub fn spawn(
+        &self,
+        (session, permit): (Arc<Session>, Option<Permit>),
+        (upstream, target): (Box<dyn UdpSession>, Socks5Target),
+        (reply, reply_to): (Arc<UdpSocket>, Option<SocketAddr>),
+        key: FlowKey,
+    ) -> Arc<UdpFlow> {
+        let flow = Arc::new(UdpFlow {
+            session,
+            upstream,
+            target,
+            reply,
+            reply_to,
+            _permit: permit,
+        });
+        self.live.lock().unwrap().insert(key, flow.clone());
+
+        tokio::spawn({
+            let (flow, live) = (flow.clone(), self.live.clone());
+            let sessions = self.sessions.clone();
+            async move {
+                let session = flow.session.clone();
+                let _registered = sessions.register(&session);
+                let result = tokio::select! {
+                    r = flow.relay() => r,
+                    _ = session.killed() => Ok("killed by admin"),
+                };
+                live.lock().unwrap().remove(&key);
+                session.log_close(&result);
+            }
+        });
+        flow
+    }
+}
+
+impl UdpFlow {
+    /// Sends a datagram from the client to the target. Datagrams beyond the
+    /// bandwidth limits are dropped, as waiting would hold up every flow.
+    pub async fn send(&self, buf: &[u8]) {
+        if !self.session.limit_up().try_consume(buf.len()) {
+            Metrics::inc(&self.session.metrics.udp_drops);
+            return;
+        }
+        match self.upstream.send_to(buf, &self.target).await {
+            Ok(()) => self.session.traffic.packet_up(buf.len()),
+            Err(_) => Metrics::inc(&self.session.metrics.udp_drops),
+        }
+    }
+
+    /// Relays replies to the client until the flow has been idle for
+    /// `UDP_IDLE_TIMEOUT`.
+    async fn relay(&self) -> Result<&'static str> {
+        let _active = self.session.metrics.udp_sessions.enter();
+        let traffic = &self.session.traffic;
+        let sent = || traffic.packets_up.load(Ordering::Relaxed);
+        let mut buf = vec![0; 65536];
+        let mut packets = sent();
+
+        loop {
+            match tokio::time::timeout(UDP_IDLE_TIMEOUT, self.upstream.recv_from(&mut buf)).await {
+                Ok(Ok((len, _))) => {
+                    self.session.limit_down().consume(len).await;
+                    match self.reply_to {
+                        Some(x) => self.reply.send_to(&buf[..len], x).await?,
+                        None => self.reply.send(&buf[..len]).await?,
+                    };
+                    traffic.packet_down(len);
+                }
+                Ok(Err(Error::Io(e))) if is_unreachable(&e) => {
+                    Metrics::inc(&self.session.metrics.udp_drops)
+                }
+                Ok(Err(e)) => return Err(e),
+                // Datagrams from the client keep the flow alive as well.
+                Err(_) if sent() != packets => packets = sent(),
+                Err(_) => return Ok("idle"),
+            }
+        }
+    }
+}
+
 impl Socks5Acceptor {
-    pub async fn associate_udp(mut self, target: Socks5Target) -> Result<()> {
+    pub async fn associate_udp(mut self, target: Socks5Target) -> Result<&'static str> {
         let mut local_addr = self.stream.local_addr()?;
         local_addr.set_port(0);
         let udp_socket = UdpSocket::bind(&local_addr).await?;
         local_addr = udp_socket.local_addr()?;
 
-        let mut client_addr = self.stream.peer_addr()?;
-        println!("{client_addr} => {local_addr} (UDP)");
+        let mut client_addr = match self.session.proxied.get() {
+            Some(x) => *x,
+            None => self.stream.peer_addr()?,
+        };
+        info!(conn = self.session.id; "{client_addr} => {local_addr} (UDP)");
         client_addr.set_port(target.1);
         self.connected(local_addr).await?;
+        self.shape();
 
         let forwarder = match Socks5UdpForwarder::bind() {
             Ok(x) => x,
             Err(e) => {
-                self.closed(1).await?;
+                self.session.metrics.reject(Reject::ServerFailure);
+                self.closed(&e).await?;
                 return Err(e);
             }
         };
         let udp_client = Socks5UdpClient::new(udp_socket, client_addr);
-        let forward_udp = forwarder.forward_udp(udp_client);
+        let session = self.session.clone();
+        let _active = session.metrics.udp_sessions.enter();
+        let forward_udp = async {
+            let (routes, rules) = (&self.config.routes, &self.config.rules);
+            match routes.iter().any(|x| x.udp.is_some()) {
+                true => forward_routed(udp_client, routes, rules, &session).await,
+                false => forwarder.forward_udp(udp_client, rules, &session).await,
+            }
+        };
 
         let done = async {
             let _ = self.stream.read(&mut [0]).await?;
-            Ok(())
+            Ok("client closed")
         };
 
         tokio::select! {
             r1 = forward_udp => {
-                r1
+                r1.map(|_| "relay closed")
             },
             r2 = done => {
                 r2
@@ -0,0 +1,151 @@
+use std::ffi::CString;
+use std::fs::{self, DirBuilder, Permissions};
+use std::mem::zeroed;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt, chown};
+use std::path::Path;
+use std::ptr::null_mut;
+
+use tokio::net::{UnixListener, UnixStream};
+
+use super::*;
+
+/// How the socket file of a Unix listener is set up.
+#[derive(Clone, Debug, Default)]
+pub struct UnixOptions {
+    /// Permission bits of the socket file, e.g. `0o660`.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl UnixOptions {
+    pub fn parse_mode(s: &str) -> std::result::Result<u32, String> {
+        u32::from_str_radix(s.trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|x| *x <= 0o7777)
+            .ok_or_else(|| format!("Invalid file mode: {s}!"))
+    }
+
+    /// Parses `USER[:GROUP]` or `:GROUP`, by name or numeric id.
+    pub fn parse_owner(s: &str) -> std::result::Result<(Option<u32>, Option<u32>), String> {
+        let (user, group) = match s.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (s, None),
+        };
+        let uid = match user {
+            "" => None,
+            x => Some(
+                x.parse()
+                    .ok()
+                    .or_else(|| lookup_user(x))
+                    .ok_or_else(|| format!("Unknown user: {x}!"))?,
+            ),
+        };
+        let gid = match group {
+            None | Some("") => None,
+            Some(x) => Some(
+                x.parse()
+                    .ok()
+                    .or_else(|| lookup_group(x))
+                    .ok_or_else(|| format!("Unknown group: {x}!"))?,
+            ),
+        };
+        Ok((uid, gid))
+    }
+
+    /// Binds a Unix listener at `path` and applies the mode and ownership to
+    /// the socket file. The socket is set up in a private directory and only
+    /// then moved into place, so nobody can connect before it is ready.
+    pub fn bind(&self, path: &Path) -> Result<UnixListener> {
+        // A socket file nobody listens on is left over from a previous run.
+        // One we may not connect to is someone else's, and left alone.
+        let stale = fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_socket())
+            && std::os::unix::net::UnixStream::connect(path)
+                .is_err_and(|e| e.kind() == ErrorKind::ConnectionRefused);
+        if stale {
+            fs::remove_file(path)?;
+        }
+
+        if self.mode.is_none() && self.uid.is_none() && self.gid.is_none() {
+            return Ok(UnixListener::bind(path)?);
+        }
+        let name = path.file_name().ok_or("Invalid socket path!")?;
+        let dir = path.with_file_name(format!(
+            ".{}.{}",
+            name.to_string_lossy(),
+            std::process::id()
+        ));
+        DirBuilder::new().mode(0o700).create(&dir)?;
+        let result = self.bind_in(&dir.join(name), path);
+        let _ = fs::remove_dir(&dir);
+        result
+    }
+
+    fn bind_in(&self, temp: &Path, path: &Path) -> Result<UnixListener> {
+        let listener = UnixListener::bind(temp)?;
+        let ready = || -> Result<()> {
+            if let Some(mode) = self.mode {
+                fs::set_permissions(temp, Permissions::from_mode(mode))?;
+            }
+            if self.uid.is_some() || self.gid.is_some() {
+                chown(temp, self.uid, self.gid)?;
+            }
+            // Unlike a rename, a link does not replace what is in the way,
+            // which fails as binding there would.
+            fs::hard_link(temp, path).map_err(|e| match e.kind() {
+                ErrorKind::AlreadyExists => io::Error::from_raw_os_error(libc::EADDRINUSE),
+                _ => e,
+            })?;
+            Ok(())
+        };
+        let result = ready();
+        let _ = fs::remove_file(temp);
+        result?;
+        Ok(listener)
+    }
+}
+
+impl Socks5Peer {
+    pub fn of_unix(stream: &UnixStream) -> io::Result<Self> {
+        let cred = stream.peer_cred()?;
+        Ok(Socks5Peer::Unix {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+
+fn lookup_user(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0; 4096];
+    let mut passwd: libc::passwd = unsafe { zeroed() };
+    let mut result = null_mut();
+    let ret = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (ret == 0 && !result.is_null()).then_some(passwd.pw_uid)
+}
+
+fn lookup_group(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok()?;
+    let mut buf = vec![0; 4096];
+    let mut group: libc::group = unsafe { zeroed() };
+    let mut result = null_mut();
+    let ret = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut group,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    (ret == 0 && !result.is_null()).then_some(group.gr_gid)
+}
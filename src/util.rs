@@ -1,22 +1,14 @@
 use std::io::IoSlice;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Shutdown, SocketAddr};
+use std::str::FromStr;
 use std::sync::Arc;
 
 use socket2::SockRef;
-use tokio::io::{self, Interest};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::{TcpStream, ToSocketAddrs, UdpSocket};
 
-use crate::error::{Error, Result};
-
-pub trait IntoResult<T> {
-    fn into_result(self) -> Result<T>;
-}
-
-impl<T> IntoResult<T> for Option<T> {
-    fn into_result(self) -> Result<T> {
-        self.ok_or_else(|| Error::from("NoneError"))
-    }
-}
+use crate::codec::{CodecError, Decode, Encode};
+use crate::error::Result;
 
 #[derive(Debug)]
 pub struct SendHalf<T>(Arc<T>);
@@ -34,8 +26,11 @@ pub trait Vectored {
     async fn send_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize>;
 }
 
-pub trait PutSocks5Addr {
-    fn put_socks5_addr(&mut self, addr: SocketAddr);
+/// An IP network such as `10.0.0.0/8` or `fd00::/8`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
 }
 
 impl Split for UdpSocket {
@@ -56,6 +51,7 @@ impl Vectored for UdpSocket {
     }
 }
 
+#[allow(unused)]
 impl RecvHalf<UdpSocket> {
     pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
         self.0.recv_from(buf).await
@@ -81,28 +77,98 @@ impl SendHalf<UdpSocket> {
     }
 }
 
-impl PutSocks5Addr for Vec<u8> {
-    fn put_socks5_addr(&mut self, mut addr: SocketAddr) {
-        if addr.is_ipv6() {
-            addr.set_ip(addr.ip().to_canonical());
+/// Reads one message from `stream`, never past its end. `buf` holds the
+/// bytes of it read so far, usually none.
+pub async fn read_message<T: Decode>(
+    stream: &mut (impl AsyncRead + Unpin + ?Sized),
+    buf: &mut Vec<u8>,
+) -> Result<T> {
+    loop {
+        match T::decode(buf) {
+            Ok((x, _)) => return Ok(x),
+            Err(CodecError::Incomplete(len)) => {
+                let start = buf.len();
+                buf.resize(len, 0);
+                stream.read_exact(&mut buf[start..]).await?;
+            }
+            Err(e) => return Err(e.into()),
         }
-        match addr {
-            SocketAddr::V4(x) => {
-                self.push(b'\x01');
-                self.extend_from_slice(&x.ip().octets());
-                self.extend_from_slice(&x.port().to_be_bytes());
+    }
+}
+
+pub async fn write_message(
+    stream: &mut (impl AsyncWrite + Unpin + ?Sized),
+    message: &impl Encode,
+) -> Result<()> {
+    let mut buf = Vec::with_capacity(32);
+    message.encode(&mut buf)?;
+    stream.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Encodes `data` as standard base64 with padding.
+pub fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk.iter().fold(0u32, |n, x| n << 8 | *x as u32) << (8 * (3 - chunk.len()));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
             }
-            SocketAddr::V6(x) => {
-                self.push(b'\x04');
-                self.extend_from_slice(&x.ip().octets());
-                self.extend_from_slice(&x.port().to_be_bytes());
+        }
+    }
+    out
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
             }
+            _ => false,
         }
     }
 }
 
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parses `ADDR/PREFIX`, or a single address.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || format!("Invalid CIDR: {s}!");
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(x) => x.parse().ok().filter(|x| *x <= max).ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Shuts down the write half of `stream`. A peer that is already gone is
+/// not an error here.
+pub fn shutdown_write(stream: &TcpStream) -> io::Result<()> {
+    match SockRef::from(stream).shutdown(Shutdown::Write) {
+        Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+        r => r,
+    }
+}
+
 #[cfg(target_family = "unix")]
-pub fn set_rlimit_nofile(limit: libc::rlim_t) -> Result<()> {
+pub fn set_rlimit_nofile(limit: u64) -> Result<()> {
     unsafe {
         let mut rlimit = libc::rlimit {
             rlim_cur: 0,
@@ -112,6 +178,7 @@ pub fn set_rlimit_nofile(limit: libc::rlim_t) -> Result<()> {
             return Err(io::Error::last_os_error().into());
         }
 
+        let limit = libc::rlim_t::try_from(limit).unwrap_or(libc::RLIM_INFINITY);
         let limit = std::cmp::min(limit, rlimit.rlim_max);
         if rlimit.rlim_cur < limit {
             rlimit.rlim_cur = limit;
@@ -0,0 +1,149 @@
+//! Runs a `Server` on loopback and talks to it with `Socks5Client`.
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use sock5s::{Error, Rule, Server, ServerBuilder, ServerHandle, Socks5Client, StaticUsers};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+
+/// Runs the server on an ephemeral port, returning its address.
+async fn start(builder: ServerBuilder) -> (String, ServerHandle) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server = builder.tcp_listener(listener).build().await.unwrap();
+    let addr = server.local_addrs()[0];
+    let handle = server.handle();
+    tokio::spawn(server.run());
+    (addr.to_string(), handle)
+}
+
+/// A TCP server echoing one connection at a time.
+async fn tcp_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        }
+    });
+    addr
+}
+
+/// A UDP server echoing every datagram.
+async fn udp_echo() -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buf = [0; 1500];
+        while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+            let _ = socket.send_to(&buf[..len], from).await;
+        }
+    });
+    addr
+}
+
+fn users() -> StaticUsers {
+    [("alice".to_owned(), "secret".to_owned())]
+        .into_iter()
+        .collect()
+}
+
+#[tokio::test]
+async fn connect() {
+    let (proxy, handle) = start(Server::builder()).await;
+    let echo = tcp_echo().await;
+
+    let mut stream = Socks5Client::new(proxy)
+        .connect(&echo.into())
+        .await
+        .unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn connect_refused() {
+    let (proxy, handle) = start(Server::builder()).await;
+    // Nothing listens on the port of a socket just closed.
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = closed.local_addr().unwrap().into();
+    drop(closed);
+
+    let result = Socks5Client::new(proxy).connect(&target).await;
+    assert!(matches!(result, Err(Error::Reply(0x05))));
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn connect_denied_by_rule() {
+    let rule: Rule = "deny=127.0.0.1/32".parse().unwrap();
+    let (proxy, handle) = start(Server::builder().rule(rule)).await;
+    let echo = tcp_echo().await;
+
+    let result = Socks5Client::new(proxy).connect(&echo.into()).await;
+    assert!(matches!(result, Err(Error::Reply(0x02))));
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn connect_denied_by_rule_after_resolving() {
+    let rules = ["deny=127.0.0.0/8", "deny=::1"].map(|x| x.parse::<Rule>().unwrap());
+    let (proxy, handle) = start(
+        Server::builder()
+            .rule(rules[0].clone())
+            .rule(rules[1].clone()),
+    )
+    .await;
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let target = format!("localhost:{port}").parse().unwrap();
+    let result = Socks5Client::new(proxy).connect(&target).await;
+    assert!(matches!(result, Err(Error::Reply(0x02))));
+    // The denied address was never connected to.
+    let accepted = tokio::time::timeout(Duration::from_millis(100), listener.accept()).await;
+    assert!(accepted.is_err());
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn user_password() {
+    let (proxy, handle) = start(Server::builder().authenticator(users())).await;
+    let echo = tcp_echo().await;
+
+    let client = Socks5Client::new(proxy.clone()).with_auth("alice", "secret");
+    let mut stream = client.connect(&echo.into()).await.unwrap();
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    let client = Socks5Client::new(proxy.clone()).with_auth("alice", "wrong");
+    let result = client.connect(&echo.into()).await;
+    assert!(matches!(result, Err(Error::Upstream(_))));
+
+    // Without credentials no method is acceptable.
+    let result = Socks5Client::new(proxy).connect(&echo.into()).await;
+    assert!(matches!(result, Err(Error::Upstream(_))));
+    handle.shutdown();
+}
+
+#[tokio::test]
+async fn udp_associate() {
+    let (proxy, handle) = start(Server::builder()).await;
+    let echo = udp_echo().await;
+
+    let socket = Socks5Client::new(proxy).associate().await.unwrap();
+    let mut buf = [0; 1500];
+    for payload in [&b"one"[..], b"two"] {
+        socket.send_to(payload, &echo.into()).await.unwrap();
+        let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..len], payload);
+        assert_eq!(from, echo.into());
+    }
+    handle.shutdown();
+}